@@ -1,3 +1,8 @@
+use std::env;
+
 fn main() {
-	println!("cargo:rustc-link-lib=framework=Security");
+	// the Security framework only exists on macOS
+	if env::var("CARGO_CFG_TARGET_OS").map(|os| os == "macos").unwrap_or(false) {
+		println!("cargo:rustc-link-lib=framework=Security");
+	}
 }
\ No newline at end of file
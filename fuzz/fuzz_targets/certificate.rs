@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sekey;
+
+use sekey::cert::Certificate;
+
+// certificates come from ssh-add and from disk, whatever parses has to encode into something that parses too
+fuzz_target!(|data: &[u8]| {
+	if let Ok(certificate) = Certificate::parse(data) {
+		assert!(Certificate::parse(&certificate.to_blob()).is_ok());
+	}
+});
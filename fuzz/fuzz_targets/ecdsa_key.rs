@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sekey;
+
+use sekey::ecdsa::EcdsaSha2;
+
+// public keys come straight from agent clients, whatever parses has to write back the same
+fuzz_target!(|data: &[u8]| {
+	if let Ok((curve, point)) = EcdsaSha2::read(data) {
+		assert_eq!(EcdsaSha2::write(curve, &point), data);
+	}
+});
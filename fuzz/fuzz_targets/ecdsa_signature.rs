@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sekey;
+
+use sekey::ecdsa::Curve;
+use sekey::key::Signature;
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(signature) = Signature::from_ecdsa_der(Curve::Nistp256, data.to_vec()) {
+		assert_eq!(Signature::from_blob(&signature.to_blob()), Ok(signature));
+	}
+});
@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sekey;
+
+use sekey::key::PublicKey;
+
+// every key type the agent understands, parsed the way sign requests are
+fuzz_target!(|data: &[u8]| {
+	if let Ok(key) = PublicKey::from_blob(data) {
+		assert_eq!(PublicKey::from_blob(&key.to_blob()), Ok(key));
+	}
+});
@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sekey;
+
+use sekey::krl::Krl;
+
+// KRLs are read back from disk before every change, whatever parses has to write back into something that parses
+fuzz_target!(|data: &[u8]| {
+	if let Ok(krl) = Krl::parse(data) {
+		assert!(Krl::parse(&krl.to_blob()).is_ok());
+	}
+});
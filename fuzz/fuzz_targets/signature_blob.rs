@@ -0,0 +1,12 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sekey;
+
+use sekey::key::Signature;
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(signature) = Signature::from_blob(data) {
+		assert_eq!(Signature::from_blob(&signature.to_blob()), Ok(signature));
+	}
+});
@@ -0,0 +1,16 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sekey;
+
+use sekey::sshsig::{AllowedSigner, SshSig};
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(signature) = SshSig::from_blob(data) {
+		assert_eq!(SshSig::from_armored(&signature.to_armored()), Ok(signature));
+	}
+	if let Ok(line) = ::std::str::from_utf8(data) {
+		let _ = SshSig::from_armored(line);
+		let _ = AllowedSigner::parse(line);
+	}
+});
@@ -0,0 +1,23 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sekey;
+
+use sekey::wire::Reader;
+
+// the first byte picks what to read next, so every mix of fields gets walked
+fuzz_target!(|data: &[u8]| {
+	let mut reader = Reader::new(data);
+	while let Ok(op) = reader.read_u8() {
+		let read = match op % 5 {
+			0 => reader.read_u32().map(|_| ()),
+			1 => reader.read_u64().map(|_| ()),
+			2 => reader.read_string().map(|_| ()),
+			3 => reader.read_mpint().map(|value| assert!(value.len() <= 1 || value[0] != 0)),
+			_ => reader.finish(),
+		};
+		if read.is_err() {
+			break;
+		}
+	}
+});
@@ -0,0 +1,212 @@
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use daemon;
+use key::Signature;
+use peer::Peer;
+use wire::{write_string, Reader};
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENT_SUCCESS: u8 = 6;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+const SSH_AGENTC_LOCK: u8 = 22;
+const SSH_AGENTC_UNLOCK: u8 = 23;
+
+// sign request flags asking RSA keys for SHA-2 signatures instead of SHA-1
+pub const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+pub const SSH_AGENT_RSA_SHA2_512: u32 = 4;
+
+// same limit OpenSSH uses for a single agent message
+const MAX_MESSAGE_LEN: u32 = 256 * 1024;
+
+pub struct Identity {
+	pub key_blob: Vec<u8>,
+	pub key_comment: String,
+}
+
+pub enum Response {
+	Success,
+	Failure,
+	Identities(Vec<Identity>),
+	SignResponse(Signature),
+}
+
+#[derive(Debug)]
+pub enum HandleError {
+	Io(io::Error),
+	Message(&'static str),
+}
+
+impl From<io::Error> for HandleError {
+	fn from(err: io::Error) -> Self {
+		HandleError::Io(err)
+	}
+}
+
+impl From<&'static str> for HandleError {
+	fn from(err: &'static str) -> Self {
+		HandleError::Message(err)
+	}
+}
+
+pub type HandleResult<T> = Result<T, HandleError>;
+
+// every request comes with the peer on the other side of the socket, so the
+// handler can make decisions based on who is asking
+pub trait SSHAgentHandler: Send {
+	fn connected(&mut self, _peer: &Peer) {}
+	fn disconnected(&mut self, _peer: &Peer) {}
+
+	fn identities(&mut self, peer: &Peer) -> HandleResult<Response>;
+	fn sign_request(&mut self, peer: &Peer, pubkey: Vec<u8>, data: Vec<u8>, flags: u32) -> HandleResult<Response>;
+
+	// `key` is the private key as sent by ssh-add: key type, key fields and comment
+	fn add_identity(&mut self, _peer: &Peer, _key: Vec<u8>) -> HandleResult<Response> {
+		Ok(Response::Failure)
+	}
+
+	fn remove_identity(&mut self, _peer: &Peer, _pubkey: Vec<u8>) -> HandleResult<Response> {
+		Ok(Response::Failure)
+	}
+
+	fn remove_all_identities(&mut self, _peer: &Peer) -> HandleResult<Response> {
+		Ok(Response::Failure)
+	}
+
+	// `ssh-add -x` and `ssh-add -X`
+	fn lock(&mut self, _peer: &Peer, _passphrase: Vec<u8>) -> HandleResult<Response> {
+		Ok(Response::Failure)
+	}
+
+	fn unlock(&mut self, _peer: &Peer, _passphrase: Vec<u8>) -> HandleResult<Response> {
+		Ok(Response::Failure)
+	}
+}
+
+pub struct Agent;
+impl Agent {
+
+	pub fn run<T: SSHAgentHandler + 'static>(handler: T, listener: UnixListener) {
+		Agent::run_shared(Arc::new(Mutex::new(handler)), listener);
+	}
+
+	// same as `run`, for a handler that is also reached from somewhere else
+	pub fn run_shared<T: SSHAgentHandler + 'static>(handler: Arc<Mutex<T>>, listener: UnixListener) {
+		for stream in listener.incoming() {
+			match stream {
+				Ok(stream) => {
+					let handler = handler.clone();
+					thread::spawn(move || {
+						let _ = Agent::handle_client(handler, stream);
+					});
+				}
+				Err(_) => continue,
+			}
+		}
+	}
+
+	fn handle_client<T: SSHAgentHandler>(handler: Arc<Mutex<T>>, stream: UnixStream) -> HandleResult<()> {
+		let peer = Peer::from_stream(&stream);
+		// only processes running as us get to use the keys, as on the control socket
+		if !daemon::same_user(peer.uid) {
+			return Ok(());
+		}
+		handler.lock().unwrap_or_else(|err| err.into_inner()).connected(&peer);
+		let result = Agent::serve(&handler, &peer, stream);
+		handler.lock().unwrap_or_else(|err| err.into_inner()).disconnected(&peer);
+		result
+	}
+
+	fn serve<T: SSHAgentHandler>(handler: &Arc<Mutex<T>>, peer: &Peer, mut stream: UnixStream) -> HandleResult<()> {
+		loop {
+			let len = match stream.read_u32::<BigEndian>() {
+				Ok(len) => len,
+				Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+				Err(err) => return Err(err.into()),
+			};
+			if len == 0 || len > MAX_MESSAGE_LEN {
+				return Err("Invalid message length".into());
+			}
+			let mut message = vec![0; len as usize];
+			stream.read_exact(&mut message)?;
+
+			let response = {
+				let mut handler = handler.lock().unwrap_or_else(|err| err.into_inner());
+				Agent::dispatch(&mut *handler, peer, &message)
+			};
+			Agent::write_response(&mut stream, response)?;
+		}
+	}
+
+	fn dispatch<T: SSHAgentHandler>(handler: &mut T, peer: &Peer, message: &[u8]) -> Response {
+		let mut reader = Reader::new(&message[1..]);
+		let response = match message[0] {
+			SSH_AGENTC_REQUEST_IDENTITIES => handler.identities(peer),
+			SSH_AGENTC_SIGN_REQUEST => {
+				Agent::read_sign_request(&mut reader)
+					.map_err(HandleError::from)
+					.and_then(|(pubkey, data, flags)| handler.sign_request(peer, pubkey, data, flags))
+			}
+			SSH_AGENTC_ADD_IDENTITY => handler.add_identity(peer, message[1..].to_vec()),
+			SSH_AGENTC_REMOVE_IDENTITY => {
+				reader.read_string()
+					.map_err(HandleError::from)
+					.and_then(|pubkey| handler.remove_identity(peer, pubkey.to_vec()))
+			}
+			SSH_AGENTC_REMOVE_ALL_IDENTITIES => handler.remove_all_identities(peer),
+			SSH_AGENTC_LOCK => {
+				reader.read_string()
+					.map_err(HandleError::from)
+					.and_then(|passphrase| handler.lock(peer, passphrase.to_vec()))
+			}
+			SSH_AGENTC_UNLOCK => {
+				reader.read_string()
+					.map_err(HandleError::from)
+					.and_then(|passphrase| handler.unlock(peer, passphrase.to_vec()))
+			}
+			// constrained keys are refused too, we can't honor the constraints
+			_ => Ok(Response::Failure),
+		};
+		response.unwrap_or(Response::Failure)
+	}
+
+	fn read_sign_request(reader: &mut Reader) -> Result<(Vec<u8>, Vec<u8>, u32), &'static str> {
+		let pubkey = reader.read_string()?.to_vec();
+		let data = reader.read_string()?.to_vec();
+		let flags = reader.read_u32()?;
+		Ok((pubkey, data, flags))
+	}
+
+	fn write_response(stream: &mut UnixStream, response: Response) -> io::Result<()> {
+		let mut message = vec![];
+		match response {
+			Response::Success => message.push(SSH_AGENT_SUCCESS),
+			Response::Failure => message.push(SSH_AGENT_FAILURE),
+			Response::Identities(identities) => {
+				message.push(SSH_AGENT_IDENTITIES_ANSWER);
+				message.write_u32::<BigEndian>(identities.len() as u32)?;
+				for identity in identities {
+					write_string(&mut message, &identity.key_blob)?;
+					write_string(&mut message, identity.key_comment.as_bytes())?;
+				}
+			}
+			Response::SignResponse(signature) => {
+				message.push(SSH_AGENT_SIGN_RESPONSE);
+				write_string(&mut message, &signature.to_blob())?;
+			}
+		}
+		stream.write_u32::<BigEndian>(message.len() as u32)?;
+		stream.write_all(&message)?;
+		stream.flush()
+	}
+}
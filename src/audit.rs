@@ -0,0 +1,47 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// append-only log of security relevant decisions taken by the agent, one line per event:
+// `<unix time> <event> key=value ...`
+pub struct Audit {
+	path: Option<PathBuf>,
+}
+
+impl Audit {
+
+	pub fn new(path: PathBuf) -> Self {
+		Self { path: Some(path) }
+	}
+
+	pub fn disabled() -> Self {
+		Self { path: None }
+	}
+
+	pub fn record(&self, event: &str, details: &[(&str, &str)]) {
+		let path = match self.path {
+			Some(ref path) => path,
+			None => return,
+		};
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+			.map(|time| time.as_secs())
+			.unwrap_or(0);
+
+		let mut line = format!("{} {}", timestamp, event);
+		for &(name, value) in details {
+			// values can contain spaces (executable paths), keep the line parseable
+			line.push_str(&format!(" {}={:?}", name, value));
+		}
+		line.push('\n');
+
+		let written = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.and_then(|mut file| file.write_all(line.as_bytes()));
+		if written.is_err() {
+			eprintln!("Error writing audit log {}", path.display());
+		}
+	}
+}
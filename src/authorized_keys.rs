@@ -0,0 +1,297 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process;
+
+use cert;
+use export::{self, Format};
+use store::PubKey;
+
+// options sshd takes without a value
+pub static FLAGS: &[&str] = &[
+	"cert-authority",
+	"restrict",
+	"no-agent-forwarding",
+	"no-port-forwarding",
+	"no-pty",
+	"no-user-rc",
+	"no-X11-forwarding",
+];
+
+// restrictions put in front of a key in authorized_keys, see sshd(8)
+#[derive(Debug, Clone, Default)]
+pub struct KeyOptions {
+	// out of FLAGS
+	pub flags: Vec<String>,
+	// host patterns or CIDR ranges the key may connect from, "!" negating one
+	pub from: Vec<String>,
+	// forced whatever the client asks to run
+	pub command: Option<String>,
+	// UTC, after which sshd refuses the key
+	pub expiry_time: Option<u64>,
+	// names that certificates signed by a cert-authority key must carry
+	pub principals: Vec<String>,
+}
+
+impl KeyOptions {
+
+	pub fn is_empty(&self) -> bool {
+		self.flags.is_empty() && self.from.is_empty() && self.command.is_none() && self.expiry_time.is_none() && self.principals.is_empty()
+	}
+
+	// values sshd would misparse or that can't be written back on one line
+	pub fn validate(&self) -> Result<(), String> {
+		for flag in &self.flags {
+			if !FLAGS.contains(&flag.as_str()) {
+				return Err(format!("Unknown option \"{}\"", flag));
+			}
+		}
+		for pattern in &self.from {
+			let valid = |c: char| c.is_ascii_alphanumeric() || ".-_:*?!/%[]".contains(c);
+			if pattern.is_empty() || !pattern.chars().all(valid) {
+				return Err(format!("Invalid from= pattern \"{}\"", pattern));
+			}
+		}
+		if let Some(ref command) = self.command {
+			if command.trim().is_empty() || command.contains(['\n', '\r']) {
+				return Err(String::from("The command has to be a single, non-empty line"));
+			}
+			// sshd would take the backslash as escaping our closing quote
+			if command.ends_with('\\') {
+				return Err(String::from("The command can't end with a backslash"));
+			}
+		}
+		for principal in &self.principals {
+			if principal.is_empty() || principal.contains(|c: char| c.is_whitespace() || c == ',' || c == '"') {
+				return Err(format!("Invalid principal \"{}\"", principal));
+			}
+		}
+		if !self.principals.is_empty() && !self.flags.iter().any(|flag| flag == "cert-authority") {
+			return Err(String::from("principals= only applies to cert-authority keys"));
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for KeyOptions {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut options: Vec<String> = self.flags.clone();
+		if !self.from.is_empty() {
+			options.push(format!("from=\"{}\"", self.from.join(",")));
+		}
+		if let Some(ref command) = self.command {
+			options.push(format!("command=\"{}\"", command.replace('"', "\\\"")));
+		}
+		if let Some(time) = self.expiry_time {
+			// YYYYMMDDHHMMSS, the Z making sshd read it as UTC rather than local time
+			let digits: String = cert::format_time(time).chars().filter(|c| c.is_ascii_digit()).collect();
+			options.push(format!("expiry-time=\"{}Z\"", digits));
+		}
+		if !self.principals.is_empty() {
+			options.push(format!("principals=\"{}\"", self.principals.join(",")));
+		}
+		write!(f, "{}", options.join(","))
+	}
+}
+
+// the authorized_keys line for `key`, its label as the comment
+pub fn entry(key: &PubKey, options: &KeyOptions) -> Result<String, String> {
+	options.validate()?;
+	let line = export::export(key, Format::OpenSsh)?;
+	if options.is_empty() {
+		return Ok(line);
+	}
+	Ok(format!("{} {}", options, line))
+}
+
+// what append changed in the file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Appended {
+	// false when the file already had the line
+	pub added: bool,
+	// lines granting the key with other options, only replaced when forced
+	pub replaced: usize,
+	// lines repeating an earlier one, options and key alike
+	pub duplicates: usize,
+}
+
+// appends `line` to the authorized_keys file at `path`, creating it if needed, and
+// drops the lines that repeat an earlier one, since sshd never gets to them. sshd
+// takes the first line for a key whose options let the login through, so any other
+// line for the same key would void the options of ours: it's an error unless `force`
+// is given, in which case those lines go.
+pub fn append(path: &Path, line: &str, force: bool) -> Result<Appended, String> {
+	let contents = match fs::read_to_string(path) {
+		Ok(contents) => contents,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+		Err(err) => return Err(format!("Error reading {}: {}", path.display(), err)),
+	};
+	let (options, key) = parse_entry(line).ok_or("Invalid authorized_keys line")?;
+
+	let mut seen = HashSet::new();
+	let mut lines = vec![];
+	let mut appended = Appended { added: true, ..Appended::default() };
+	for (number, existing) in contents.lines().enumerate() {
+		match parse_entry(existing) {
+			Some(entry) if entry.1 == key => {
+				if entry.0 == options && appended.added {
+					appended.added = false;
+					lines.push(existing);
+				} else if entry.0 == options {
+					appended.duplicates += 1;
+				} else if force {
+					appended.replaced += 1;
+				} else {
+					return Err(format!("{}:{} already grants the key with other options, use --force to replace it: {}",
+						path.display(), number + 1, existing.trim()));
+				}
+			}
+			Some(entry) => {
+				if seen.insert(entry) {
+					lines.push(existing);
+				} else {
+					appended.duplicates += 1;
+				}
+			}
+			None => lines.push(existing),
+		}
+	}
+	if appended.added {
+		lines.push(line);
+	}
+	if appended == Appended::default() {
+		return Ok(appended);
+	}
+
+	let mut output = lines.join("\n");
+	output.push('\n');
+	write_file(path, &output).map_err(|err| format!("Error writing {}: {}", path.display(), err))?;
+	Ok(appended)
+}
+
+// the options and key of a line, comments aside. None for blank lines, comments and
+// lines we can't make sense of, which are kept as they are.
+fn parse_entry(line: &str) -> Option<(String, Vec<u8>)> {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with('#') {
+		return None;
+	}
+	if let Ok((blob, _)) = cert::parse_public_key(line) {
+		return Some((String::new(), blob));
+	}
+
+	let (options, key) = split_options(line);
+	cert::parse_public_key(key).ok().map(|(blob, _)| (options.to_string(), blob))
+}
+
+// the leading field of `line` and the rest: up to the first blank outside quotes, a
+// backslash escaping a quote, as sshd reads options
+pub fn split_options(line: &str) -> (&str, &str) {
+	let mut quoted = false;
+	let mut end = line.len();
+	let mut chars = line.char_indices().peekable();
+	while let Some((index, c)) = chars.next() {
+		if !quoted && (c == ' ' || c == '\t') {
+			end = index;
+			break;
+		}
+		if c == '\\' && chars.peek().map(|&(_, next)| next) == Some('"') {
+			chars.next();
+		} else if c == '"' {
+			quoted = !quoted;
+		}
+	}
+	let (field, rest) = line.split_at(end);
+	(field, rest.trim_start())
+}
+
+// written next to the file and renamed over it, so sshd never reads half of it and a
+// failed write leaves the old one. An existing file keeps its mode, a new one gets 0600
+// as sshd's StrictModes ignores the file once others can write to it.
+fn write_file(path: &Path, contents: &str) -> io::Result<()> {
+	// through symlinks, replacing the file rather than the link
+	let path = match fs::canonicalize(path) {
+		Ok(path) => path,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => path.to_path_buf(),
+		Err(err) => return Err(err),
+	};
+	let mode = match fs::metadata(&path) {
+		Ok(metadata) => metadata.permissions().mode() & 0o7777,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0o600,
+		Err(err) => return Err(err),
+	};
+	let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+	let mut temp_name = OsString::from(".");
+	temp_name.push(name);
+	temp_name.push(format!(".sekey-{}", process::id()));
+	let temp = path.with_file_name(temp_name);
+
+	let written = fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(0o600)
+		.open(&temp)
+		.and_then(|mut file| {
+			file.write_all(contents.as_bytes())?;
+			file.set_permissions(fs::Permissions::from_mode(mode))?;
+			file.sync_all()
+		})
+		.and_then(|_| fs::rename(&temp, &path));
+	if written.is_err() {
+		let _ = fs::remove_file(&temp);
+	}
+	written
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::path::PathBuf;
+
+	const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIIb4aoZ2MEYYQUTOFkAyLqPa+7Ml/muhTl8x5azkeYdQ sekey test";
+	const OTHER_KEY: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBL6ULOqxSGsMaXeBEVlQKpRvhamzLiRGGH8yTSQWN72NRpVFFRLOZqdmiU6T6EXVUYAe7VmHSqSrN+e4dAaRl1M= other";
+
+	// an authorized_keys file of its own for every test
+	fn authorized_keys(name: &str, contents: &str) -> PathBuf {
+		let path = env::temp_dir().join(format!("sekey-authorized-keys-{}-{}", name, process::id()));
+		fs::write(&path, contents).unwrap();
+		path
+	}
+
+	#[test]
+	fn appends_lines_once() {
+		let path = authorized_keys("once", &format!("{}\n", OTHER_KEY));
+		let line = format!("restrict {}", KEY);
+		assert_eq!(append(&path, &line, false), Ok(Appended { added: true, ..Appended::default() }));
+		assert_eq!(append(&path, &line, false), Ok(Appended::default()));
+		assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n{}\n", OTHER_KEY, line));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn refuses_lines_granting_the_key_with_other_options() {
+		let path = authorized_keys("other-options", &format!("# keys\n{}\n", KEY));
+		let line = format!("restrict,from=\"10.0.0.0/8\" {}", KEY);
+		let err = append(&path, &line, false).unwrap_err();
+		assert!(err.contains(":2 already grants the key"), "{}", err);
+		assert_eq!(fs::read_to_string(&path).unwrap(), format!("# keys\n{}\n", KEY));
+
+		assert_eq!(append(&path, &line, true), Ok(Appended { added: true, replaced: 1, duplicates: 0 }));
+		assert_eq!(fs::read_to_string(&path).unwrap(), format!("# keys\n{}\n", line));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn drops_duplicate_lines() {
+		let line = format!("no-pty {}", KEY);
+		let path = authorized_keys("duplicates", &format!("{}\n{}\n{} copy\n{}\n", OTHER_KEY, line, OTHER_KEY, line));
+		assert_eq!(append(&path, &line, false), Ok(Appended { added: false, replaced: 0, duplicates: 2 }));
+		assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n{}\n", OTHER_KEY, line));
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -1,157 +1,1326 @@
+// struct literals are written out as `field: field` throughout
+#![allow(clippy::redundant_field_names)]
+
 extern crate sekey;
 extern crate env_logger;
-extern crate ssh_agent;
 extern crate clap;
 #[macro_use]
 extern crate prettytable;
-extern crate hex;
-extern crate base64;
+extern crate libc;
+extern crate serde_json;
 
 
-use std::os::unix::net::{UnixListener};
-use clap::{App, Arg};
+use std::os::unix::net::UnixStream;
+use clap::{App, Arg, SubCommand};
 
 use prettytable::Table;
 use prettytable::format;
 
-use sekey::Keychain;
-use sekey::ecdsa::{EcdsaSha2Nistp256, CURVE_TYPE};
-use sekey::handler::Handler;
-use ssh_agent::SSHAgentHandler;
-use std::fs;
-use std::path::Path;
-use std::path::PathBuf;
+use sekey::handler::{Handler, Settings};
+use sekey::agent::Agent;
+use sekey::audit::Audit;
+use sekey::authorized_keys::{self, KeyOptions};
+use sekey::cert::{self, CertType, Certificate};
+use sekey::config::Config;
+use sekey::control::{Command, ControlClient, ControlServer};
+use sekey::events::{EventBus, EventServer};
+use sekey::export::{self, EXPORT_FORMATS};
+use sekey::fingerprint;
+use sekey::policy::{LimitsPolicy, Policy};
+use sekey::store::{self, KeyStore};
+use sekey::daemon::{self, Fork};
+use sekey::home;
+use sekey::install;
+use sekey::krl::{self, Krl};
+use sekey::shell::Shell;
+use sekey::sshsig::{self, SshSig, HASH_ALGORITHMS};
+use std::collections::BTreeMap;
 use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+
+fn shell_from_matches(matches: &clap::ArgMatches) -> Option<Shell> {
+    if matches.is_present("sh") {
+        Some(Shell::Sh)
+    } else if matches.is_present("csh") {
+        Some(Shell::Csh)
+    } else if matches.is_present("fish") {
+        Some(Shell::Fish)
+    } else {
+        None
+    }
+}
+
+// --config given after a subcommand is only recorded in that subcommand's matches
+fn config_path(matches: &clap::ArgMatches) -> Result<PathBuf, String> {
+    let mut value = matches.value_of("config");
+    let mut current = matches;
+    while let Some(sub_matches) = current.subcommand().1 {
+        value = sub_matches.value_of("config").or(value);
+        current = sub_matches;
+    }
+    match value {
+        Some(path) => Ok(home::absolute(&PathBuf::from(path))),
+        None => Config::default_path().map_err(String::from),
+    }
+}
+
+fn load_config(matches: &clap::ArgMatches) -> Result<Config, String> {
+    Config::load(&config_path(matches)?)
+}
+
+fn init_logging(config: &Config) {
+    let mut builder = env_logger::LogBuilder::new();
+    builder.parse(&config.logging.level);
+    // RUST_LOG still wins, handy while debugging
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    builder.init().unwrap_or_else(|err| {
+        eprintln!("logger init error {}", err);
+    });
+}
 
+// limits given on the command line, they win over the policy file
+fn limit_overrides(matches: &clap::ArgMatches) -> Result<LimitsPolicy, String> {
+    let mut limits = LimitsPolicy {
+        key: matches.value_of("limit-key").map(String::from),
+        peer: matches.value_of("limit-peer").map(String::from),
+        global: matches.value_of("limit-global").map(String::from),
+        hook: matches.value_of("limit-hook").map(String::from),
+        ..LimitsPolicy::default()
+    };
+    if let Some(threshold) = matches.value_of("limit-hook-threshold") {
+        let threshold = threshold.parse::<u32>()
+            .map_err(|_| String::from("--limit-hook-threshold: Invalid count"))?;
+        limits.hook_threshold = Some(threshold);
+    }
+    Ok(limits)
+}
 
+// everything the daemon reads from disk, called again on every reload
+fn load_settings(path: &Path, overrides: &LimitsPolicy) -> Result<Settings, String> {
+    let config = Config::load(path)?;
+    let limiter = Policy::load(&config.policy_path()?)?.limits
+        .merge(overrides)
+        .rate_limiter()?;
+    let audit = match config.audit_path()? {
+        Some(path) => Audit::new(path),
+        None => Audit::disabled(),
+    };
+    Ok(Settings { config: config, limiter: limiter, audit: audit })
+}
 
-static SEKEY_HOME_FOLDER: &'static str = "/.sekey/";
-static SSH_AGENT_PIPE: &'static str = "ssh-agent.ssh";
+fn table_format(config: &Config) -> format::TableFormat {
+    if config.list.table_format == "plain" {
+        return format::FormatBuilder::new()
+            .column_separator(' ')
+            .padding(0, 2)
+            .build();
+    }
+    format::FormatBuilder::new()
+        .column_separator('│')
+        .borders('│')
+        .separators(&[format::LinePosition::Top],
+            format::LineSeparator::new('─', '┬', '┌', '┐'))
+        .separators(&[format::LinePosition::Title],
+            format::LineSeparator::new('─', '┼', '├', '┤'))
+        .separators(&[format::LinePosition::Bottom],
+            format::LineSeparator::new('─', '┴', '└', '┘'))
+
+        .padding(5, 5)
+        .build()
+}
 
-fn create_home_path(home: PathBuf) -> Result<(), &'static str> {
-    let home = format!("{}{}", home.display(), SEKEY_HOME_FOLDER);
-    let home = Path::new(home.as_str());
-    if !Path::new(home).exists() {
-        match fs::create_dir(home){
-            Ok(_) => Ok(()),
-            Err(_) => Err("Error creating home folder"),
+fn check_config(matches: &clap::ArgMatches) -> Result<(), String> {
+    let config = load_config(matches)?;
+    let problems = config.check();
+    if problems.is_empty() {
+        println!("Configuration OK");
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    Err(format!("{} problem(s) found", problems.len()))
+}
+
+fn socket_path(matches: &clap::ArgMatches, config: &Config) -> Result<PathBuf, String> {
+    match matches.value_of("socket") {
+        Some(socket) => Ok(home::absolute(&PathBuf::from(socket))),
+        None => config.agent_socket().map_err(String::from),
+    }
+}
+
+fn print_environment(shell: Shell, socket: &Path, pid: u32) {
+    print!("{}", shell.export("SSH_AUTH_SOCK", &socket.display().to_string()));
+    print!("{}", shell.export("SSH_AGENT_PID", &pid.to_string()));
+    println!("echo Agent pid {};", pid);
+    let _ = io::stdout().flush();
+}
+
+// a failed reload is recorded in the audit log, the daemon has no terminal to report to
+fn reload_on_hangup(handler: Arc<Mutex<Handler>>) -> impl Fn() + Send + 'static {
+    move || {
+        let mut handler = handler.lock().unwrap_or_else(|err| err.into_inner());
+        if let Err(err) = handler.reload("signal:SIGHUP") {
+            eprintln!("Reload failed, keeping the old configuration: {}", err);
+        }
+    }
+}
+
+fn run_daemon(matches: &clap::ArgMatches) -> Result<(), String> {
+    home::create_sekey_home()?;
+    let path = config_path(matches)?;
+    let overrides = limit_overrides(matches)?;
+    let settings = load_settings(&path, &overrides)?;
+    let config = settings.config.clone();
+
+    let pipe = socket_path(matches, &config)?;
+    let pidfile = daemon::pidfile_path(&pipe);
+    let control = config.control_socket(&pipe);
+    let events = config.events_socket(&pipe);
+    let bus = EventBus::new();
+    let store = KeyStore::from_config(&config)?;
+    let mut handler = Handler::new(store, settings.config, settings.limiter, settings.audit);
+    handler.set_loader(Box::new(move || load_settings(&path, &overrides)));
+    handler.set_events(bus.clone());
+    let handler = Arc::new(Mutex::new(handler));
+
+    // under socket activation systemd owns the socket, we just serve it in the foreground
+    if let Some(listener) = daemon::activated_listener() {
+        eprintln!("using socket passed by systemd");
+        daemon::write_pidfile(&pidfile)?;
+        daemon::handle_signals(vec![pidfile, control.clone(), events.clone()], reload_on_hangup(handler.clone()));
+        ControlServer::spawn(handler.clone(), pipe, &control)?;
+        EventServer::spawn(bus, &events)?;
+        Agent::run_shared(handler, listener);
+        return Ok(());
+    }
+
+    // refuses to start when another agent is alive, and cleans up after one that died
+    daemon::check_not_running(&pipe, &pidfile)?;
+
+    // stdout is reserved for the environment commands, so `eval $(sekey --daemon -s)` works
+    eprintln!("binding to {}", pipe.display());
+    let listener = daemon::bind_private(&pipe)?;
+
+    if matches.is_present("foreground") {
+        if let Some(shell) = shell_from_matches(matches) {
+            print_environment(shell, &pipe, std::process::id());
         }
     } else {
-        Ok(())
+        match daemon::daemonize()? {
+            Fork::Parent(pid) => {
+                let shell = shell_from_matches(matches).unwrap_or_else(Shell::detect);
+                print_environment(shell, &pipe, pid as u32);
+                return Ok(());
+            }
+            Fork::Child => {}
+        }
     }
+
+    daemon::write_pidfile(&pidfile)?;
+    daemon::handle_signals(vec![pipe.clone(), pidfile, control.clone(), events.clone()], reload_on_hangup(handler.clone()));
+    ControlServer::spawn(handler.clone(), pipe, &control)?;
+    EventServer::spawn(bus, &events)?;
+
+    Agent::run_shared(handler, listener);
+    Ok(())
 }
 
-fn main() {
-    env_logger::init().unwrap_or_else(|err| {
-        eprintln!("logger init error {}", err);
-    });
+// reads a passphrase from the terminal without echoing it
+fn read_passphrase(prompt: &str) -> Result<String, String> {
+    eprint!("{}", prompt);
+    let _ = io::stderr().flush();
+
+    let fd = libc::STDIN_FILENO;
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    let is_tty = unsafe { libc::isatty(fd) == 1 && libc::tcgetattr(fd, &mut original) == 0 };
+    if is_tty {
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+
+    let mut passphrase = String::new();
+    let read = io::stdin().lock().read_line(&mut passphrase);
+    if is_tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+        eprintln!();
+    }
+    read.map_err(|err| format!("Error reading passphrase: {}", err))?;
+    while passphrase.ends_with('\n') || passphrase.ends_with('\r') {
+        passphrase.pop();
+    }
+    Ok(passphrase)
+}
+
+// `NAME` or `NAME=VALUE`
+fn split_option(option: &str) -> (String, String) {
+    match option.find('=') {
+        Some(position) => (option[..position].to_string(), option[position + 1..].to_string()),
+        None => (option.to_string(), String::new()),
+    }
+}
+
+// next to the key, like ssh-keygen: id_ecdsa.pub gives id_ecdsa-cert.pub
+fn cert_output_path(key_path: &Path) -> PathBuf {
+    let path = key_path.display().to_string();
+    let stem = if path.ends_with(".pub") { &path[..path.len() - 4] } else { path.as_str() };
+    PathBuf::from(format!("{}-cert.pub", stem))
+}
+
+fn sign_certificate(matches: &clap::ArgMatches, store: &mut KeyStore) -> Result<(), String> {
+    let key_path = PathBuf::from(matches.value_of("public-key").unwrap_or_default());
+    let contents = fs::read_to_string(&key_path)
+        .map_err(|err| format!("Error reading {}: {}", key_path.display(), err))?;
+    let (key, comment) = cert::parse_public_key(&contents)?;
+    let ca_id = store.select(matches.value_of("ca").unwrap_or_default())?.pubkey.id;
+
+    let mut certificate = Certificate::for_key(&key)?;
+    certificate.key_id = matches.value_of("key-id").unwrap_or_default().to_string();
+    if let Some(serial) = matches.value_of("serial") {
+        certificate.serial = serial.parse::<u64>().map_err(|_| "Invalid serial number")?;
+    }
+    if let Some(principals) = matches.value_of("principals") {
+        certificate.principals = principals.split(',')
+            .map(|principal| principal.trim().to_string())
+            .filter(|principal| !principal.is_empty())
+            .collect();
+    }
+    if matches.is_present("host") {
+        certificate.cert_type = CertType::Host;
+        certificate.extensions.clear();
+    }
+    if let Some(interval) = matches.value_of("valid") {
+        let (valid_after, valid_before) = cert::parse_validity(interval, cert::now())?;
+        certificate.valid_after = valid_after;
+        certificate.valid_before = valid_before;
+    }
+    if matches.is_present("clear-extensions") {
+        certificate.extensions.clear();
+    }
+    for option in matches.values_of("critical").into_iter().flatten() {
+        let (name, value) = split_option(option);
+        if !cert::CRITICAL_OPTIONS.contains(&name.as_str()) {
+            return Err(format!("Unknown critical option {}, expected one of {}", name, cert::CRITICAL_OPTIONS.join(", ")));
+        }
+        certificate.critical_options.insert(name, value);
+    }
+    for extension in matches.values_of("extension").into_iter().flatten() {
+        let (name, value) = split_option(extension);
+        certificate.extensions.insert(name, value);
+    }
+    if certificate.principals.is_empty() {
+        eprintln!("Warning: a certificate without principals is valid for any {}", if certificate.cert_type == CertType::Host { "host" } else { "user" });
+    }
+
+    certificate.sign(store, &ca_id)?;
+
+    let output = matches.value_of("output").map(PathBuf::from).unwrap_or_else(|| cert_output_path(&key_path));
+    fs::write(&output, format!("{}\n", certificate.to_openssh(&comment)))
+        .map_err(|err| format!("Error writing {}: {}", output.display(), err))?;
+    println!("Signed {} certificate {} id \"{}\" serial {}", certificate.cert_type.name(), output.display(), certificate.key_id, certificate.serial);
+    Ok(())
+}
+
+// how close a certificate is to expiring, flagging the ones inside the warning window
+fn certificate_status(certificate: &Certificate, now: u64, warning: u64) -> String {
+    if certificate.valid_before <= now {
+        format!("EXPIRED {} ago", cert::format_duration(now - certificate.valid_before))
+    } else if certificate.valid_after > now {
+        format!("not valid for another {}", cert::format_duration(certificate.valid_after - now))
+    } else if certificate.valid_before == cert::FOREVER {
+        String::from("never expires")
+    } else if certificate.valid_before - now <= warning {
+        format!("EXPIRES in {}", cert::format_duration(certificate.valid_before - now))
+    } else {
+        format!("expires in {}", cert::format_duration(certificate.valid_before - now))
+    }
+}
+
+fn print_options(title: &str, options: &BTreeMap<String, String>) {
+    if options.is_empty() {
+        println!("        {}: (none)", title);
+        return;
+    }
+    println!("        {}: ", title);
+    for (name, value) in options {
+        if value.is_empty() {
+            println!("                {}", name);
+        } else {
+            println!("                {} {}", name, value);
+        }
+    }
+}
+
+// decodes certificates the way ssh-keygen -L does, plus how long they have left
+fn show_certificate(path: &Path, config: &Config) -> Result<(), String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Error reading {}: {}", path.display(), err))?;
+    let (blob, _) = cert::parse_public_key(&contents)?;
+    let certificate = Certificate::parse(&blob)?;
+
+    println!("{}:", path.display());
+    println!("        Type: {} {} certificate", cert::cert_key_type(&certificate.key_type)?, certificate.cert_type.name());
+    println!("        Public key: {}-CERT {}", cert::key_type_name(&certificate.key_type), fingerprint::sha256(&certificate.subject_key()));
+    println!("        Signing CA: {} {} (using {})", cert::key_type_name(&cert::blob_key_type(&certificate.signature_key)), fingerprint::sha256(&certificate.signature_key), certificate.signature_algorithm());
+    println!("        Key ID: \"{}\"", certificate.key_id);
+    println!("        Serial: {}", certificate.serial);
+    println!("        Valid: {}", certificate.validity());
+    println!("        Status: {}", certificate_status(&certificate, cert::now(), config.certificate_warning()));
+    if certificate.principals.is_empty() {
+        println!("        Principals: (none)");
+    } else {
+        println!("        Principals: ");
+        for principal in &certificate.principals {
+            println!("                {}", principal);
+        }
+    }
+    print_options("Critical Options", &certificate.critical_options);
+    print_options("Extensions", &certificate.extensions);
+    Ok(())
+}
+
+fn read_public_key(path: &str) -> Result<Vec<u8>, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("Error reading {}: {}", path, err))?;
+    cert::parse_public_key(&contents).map(|(blob, _)| blob).map_err(|err| format!("{}: {}", path, err))
+}
+
+// adds revocations to a KRL, creating it if needed, and signs it with the CA key
+fn update_krl(matches: &clap::ArgMatches, store: &mut KeyStore) -> Result<(), String> {
+    let path = PathBuf::from(matches.value_of("krl").unwrap_or_default());
+    let mut krl = match fs::read(&path) {
+        Ok(blob) => Krl::parse(&blob).map_err(|err| format!("{}: {}", path.display(), err))?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Krl::new(),
+        Err(err) => return Err(format!("Error reading {}: {}", path.display(), err)),
+    };
+
+    let ca_key = match matches.value_of("ca") {
+        Some(ca) => Some(store.select(ca)?),
+        None => None,
+    };
+    let ca_id = ca_key.as_ref().map(|key| key.pubkey.id.clone());
+    let ca = ca_key.map(|key| key.blob());
+    let serials: Vec<&str> = matches.values_of("serial").map(|values| values.collect()).unwrap_or_default();
+    let key_ids: Vec<&str> = matches.values_of("key-id").map(|values| values.collect()).unwrap_or_default();
+    if ca.is_none() && (!serials.is_empty() || !key_ids.is_empty()) {
+        return Err(String::from("Revoking by serial or key ID needs the CA, use --ca"));
+    }
+
+    for serial in &serials {
+        let (low, high) = krl::parse_serials(serial)?;
+        krl.revoke_serials(ca.as_ref().unwrap(), low, high);
+    }
+    for key_id in &key_ids {
+        krl.revoke_key_id(ca.as_ref().unwrap(), key_id);
+    }
+    let keys: Vec<&str> = matches.values_of("key").map(|values| values.collect()).unwrap_or_default();
+    for key in &keys {
+        krl.revoke_key(&read_public_key(key)?).map_err(|err| format!("{}: {}", key, err))?;
+    }
+    if let Some(comment) = matches.value_of("comment") {
+        krl.comment = comment.to_string();
+    }
+
+    krl.touch(cert::now());
+    if let Some(ref ca_id) = ca_id {
+        krl.sign(store, ca_id)?;
+    }
+    fs::write(&path, krl.to_blob()).map_err(|err| format!("Error writing {}: {}", path.display(), err))?;
+    println!("Updated KRL {} to version {}: revoked {} serial range(s), {} key ID(s) and {} key(s){}",
+             path.display(), krl.version, serials.len(), key_ids.len(), keys.len(),
+             if ca_id.is_some() { ", signed by the CA" } else { "" });
+    Ok(())
+}
+
+fn authorize_key(matches: &clap::ArgMatches, store: &mut KeyStore) -> Result<(), String> {
+    let key = store.select(matches.value_of("key").unwrap_or_default())?;
+    let list = |name: &str| matches.value_of(name)
+        .map(|value| value.split(',').map(|item| item.trim().to_string()).collect())
+        .unwrap_or_default();
+
+    let mut options = KeyOptions {
+        // the flags are named after the options, in lower case
+        flags: authorized_keys::FLAGS.iter()
+            .filter(|flag| matches.is_present(flag.to_lowercase()))
+            .map(|flag| flag.to_string())
+            .collect(),
+        from: list("from"),
+        command: matches.value_of("command").map(String::from),
+        principals: list("principals"),
+        ..KeyOptions::default()
+    };
+    if let Some(time) = matches.value_of("expiry-time") {
+        let now = cert::now();
+        let expiry = cert::parse_time(time, now)?;
+        if expiry == 0 || expiry == cert::FOREVER {
+            return Err(format!("Invalid expiry time \"{}\"", time));
+        }
+        if expiry <= now {
+            return Err(format!("Expiry time {} is in the past", cert::format_time(expiry)));
+        }
+        options.expiry_time = Some(expiry);
+    }
+
+    let line = authorized_keys::entry(&key.pubkey, &options)?;
+    let path = match matches.value_of("file") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            println!("{}", line);
+            return Ok(());
+        }
+    };
+    let appended = authorized_keys::append(&path, &line, matches.is_present("force"))?;
+    if appended.added {
+        println!("Authorized {} in {}", key.pubkey.label, path.display());
+    } else {
+        println!("{} is already authorized in {}", key.pubkey.label, path.display());
+    }
+    if appended.replaced > 0 {
+        println!("Replaced {} line(s) granting the key with other options", appended.replaced);
+    }
+    if appended.duplicates > 0 {
+        println!("Removed {} duplicate line(s)", appended.duplicates);
+    }
+    Ok(())
+}
+
+fn open_message(path: Option<&str>) -> Result<Box<dyn io::Read>, String> {
+    match path {
+        Some(path) => fs::File::open(path)
+            .map(|file| Box::new(file) as Box<dyn io::Read>)
+            .map_err(|err| format!("Error reading {}: {}", path, err)),
+        None => Ok(Box::new(io::stdin())),
+    }
+}
+
+fn sign_messages(matches: &clap::ArgMatches, store: &mut KeyStore) -> Result<(), String> {
+    let key = store.select(matches.value_of("key").unwrap_or_default())?;
+    let namespace = matches.value_of("namespace").unwrap_or_default();
+    let hash_algorithm = matches.value_of("hash").unwrap_or(sshsig::DEFAULT_HASH_ALGORITHM);
+    let sign = |store: &mut KeyStore, path: Option<&str>| -> Result<SshSig, String> {
+        let digest = sshsig::digest(hash_algorithm, &mut open_message(path)?)?;
+        Ok(SshSig::sign(store, &key.pubkey.id, namespace, hash_algorithm, &digest)?)
+    };
+
+    // like ssh-keygen -Y sign: stdin to stdout, or each file to <file>.sig
+    let files: Vec<&str> = matches.values_of("file").map(|values| values.collect()).unwrap_or_default();
+    if files.is_empty() {
+        println!("{}", sign(store, None)?.to_armored());
+    }
+    for file in files {
+        let signature = sign(store, Some(file))?;
+        let output = format!("{}.sig", file);
+        fs::write(&output, format!("{}\n", signature.to_armored()))
+            .map_err(|err| format!("Error writing {}: {}", output, err))?;
+        println!("Signed {} with {}, signature written to {}", file, key.pubkey.label, output);
+    }
+    Ok(())
+}
+
+fn verify_message(matches: &clap::ArgMatches) -> Result<(), String> {
+    let path = matches.value_of("signature").unwrap_or_default();
+    let armored = fs::read_to_string(path).map_err(|err| format!("Error reading {}: {}", path, err))?;
+    let signature = SshSig::from_armored(&armored).map_err(|err| format!("{}: {}", path, err))?;
+    let namespace = matches.value_of("namespace").unwrap_or_default();
+    let principal = matches.value_of("identity").unwrap_or_default();
+
+    let digest = sshsig::digest(&signature.hash_algorithm, &mut open_message(matches.value_of("file"))?)?;
+    signature.verify(namespace, &digest)?;
+
+    let signers_path = matches.value_of("allowed-signers").unwrap_or_default();
+    let contents = fs::read_to_string(signers_path).map_err(|err| format!("Error reading {}: {}", signers_path, err))?;
+    let key = &signature.public_key;
+    let description = format!("{} key {}", cert::key_type_name(key.key_type()), key.fingerprint());
+    let allowed = sshsig::parse_allowed_signers(signers_path, &contents).iter()
+        .any(|signer| signer.allows(principal, namespace, key, cert::now()));
+    if !allowed {
+        return Err(format!("{} isn't allowed to sign for \"{}\" with {}", principal, namespace, description));
+    }
+    println!("Good \"{}\" signature for {} with {}", namespace, principal, description);
+    Ok(())
+}
+
+// like ssh-keygen -Q, true when every key passed
+fn check_krl(matches: &clap::ArgMatches) -> Result<bool, String> {
+    let path = matches.value_of("krl").unwrap_or_default();
+    let blob = fs::read(path).map_err(|err| format!("Error reading {}: {}", path, err))?;
+    let krl = Krl::parse(&blob).map_err(|err| format!("{}: {}", path, err))?;
+
+    let mut passed = true;
+    for key in matches.values_of("key").into_iter().flatten() {
+        match krl.revocation(&read_public_key(key)?) {
+            Some(reason) => {
+                println!("{}: REVOKED ({})", key, reason);
+                passed = false;
+            }
+            None => println!("{}: ok", key),
+        }
+    }
+    Ok(passed)
+}
+
+// tells a running agent about keys generated or deleted here, it's fine if there is none
+fn notify_agent(matches: &clap::ArgMatches, config: &Config) {
+    let control = match socket_path(matches, config) {
+        Ok(pipe) => config.control_socket(&pipe),
+        Err(_) => return,
+    };
+    if let Ok(mut client) = ControlClient::connect(&control) {
+        let _ = client.call(Command::RefreshKeys);
+    }
+}
+
+// copies the event stream to stdout until the agent goes away
+fn watch_events(path: &Path) -> Result<(), String> {
+    let stream = UnixStream::connect(path)
+        .map_err(|err| format!("Error connecting to {}: {}", path.display(), err))?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for line in io::BufReader::new(stream).lines() {
+        let line = line.map_err(|err| format!("Error reading events: {}", err))?;
+        if writeln!(stdout, "{}", line).and_then(|_| stdout.flush()).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn run_ctl(matches: &clap::ArgMatches, config: &Config) -> Result<(), String> {
+    let pipe = socket_path(matches, config)?;
+    if matches.subcommand_matches("events").is_some() {
+        return watch_events(&config.events_socket(&pipe));
+    }
+    let mut client = ControlClient::connect(&config.control_socket(&pipe))?;
+    let json = matches.is_present("json");
+
+    let (name, sub) = matches.subcommand();
+    let sub = sub.ok_or("Missing command, see sekey ctl --help")?;
+    let command = match name {
+        "status" => Command::Status,
+        "identities" => Command::Identities,
+        "reload" => Command::Reload,
+        "refresh-keys" => Command::RefreshKeys,
+        "lock" | "unlock" => {
+            let passphrase = if sub.is_present("passphrase") {
+                Some(read_passphrase("Passphrase: ")?)
+            } else {
+                None
+            };
+            if name == "lock" {
+                Command::Lock { passphrase: passphrase }
+            } else {
+                Command::Unlock { passphrase: passphrase }
+            }
+        }
+        "drop-keys" => Command::DropKeys { backend: sub.value_of("backend").unwrap_or("software").to_string() },
+        "counters" => Command::Counters,
+        _ => return Err(String::from("Missing command, see sekey ctl --help")),
+    };
+
+    if json {
+        let result = client.call(command)?;
+        println!("{}", serde_json::to_string_pretty(&result).map_err(|err| err.to_string())?);
+        return Ok(());
+    }
 
+    match command {
+        Command::Status => {
+            let status = client.status()?;
+            println!("sekey {} (protocol {})", status.version, status.protocol);
+            println!("pid:      {}", status.pid);
+            println!("uptime:   {}s", status.uptime);
+            println!("socket:   {}", status.agent_socket);
+            println!("locked:   {}", if status.locked { "yes" } else { "no" });
+            println!("backends: {}", status.backends.join(", "));
+            println!("keys:     {}", status.keys);
+        }
+        Command::Identities => {
+            let identities = client.identities()?;
+            if identities.is_empty() {
+                println!("No keys loaded");
+                return Ok(());
+            }
+            let mut table = Table::new();
+            table.set_format(table_format(config));
+            table.set_titles(row![bc => "Label", "ID", "Fingerprint", "Backend", "Source"]);
+            for identity in identities {
+                table.add_row(row![identity.label, identity.id, identity.fingerprint, identity.backend, identity.source]);
+            }
+            table.printstd();
+        }
+        Command::Counters => {
+            let counters = client.counters()?;
+            println!("identity requests:  {}", counters.identity_requests);
+            println!("sign requests:      {}", counters.sign_requests);
+            println!("signatures:         {}", counters.signatures);
+            println!("sign errors:        {}", counters.sign_errors);
+            println!("rate limited:       {}", counters.rate_limited);
+            println!("refused locked:     {}", counters.refused_locked);
+            println!("identities added:   {}", counters.identities_added);
+            println!("identities removed: {}", counters.identities_removed);
+            println!("reloads:            {}", counters.reloads);
+        }
+        Command::DropKeys { backend } => {
+            let dropped = client.drop_keys(&backend)?;
+            println!("Dropped {} key(s) from the {} backend", dropped.keys, dropped.backend);
+        }
+        command => {
+            client.call(command)?;
+            println!("OK");
+        }
+    }
+    Ok(())
+}
+
+fn kill_daemon(matches: &clap::ArgMatches, config: &Config) -> Result<(), String> {
+    let pipe = socket_path(matches, config)?;
+    // only our own pidfile, $SSH_AGENT_PID may well name an ssh-agent or a reused pid
+    let pid = daemon::read_pidfile(&daemon::pidfile_path(&pipe))
+        .ok_or("No sekey agent running")?;
+    daemon::kill(pid)?;
+
+    let shell = shell_from_matches(matches).unwrap_or_else(Shell::detect);
+    print!("{}", shell.unset("SSH_AUTH_SOCK"));
+    print!("{}", shell.unset("SSH_AGENT_PID"));
+    println!("echo Agent pid {} killed;", pid);
+    Ok(())
+}
+
+fn install_service(matches: &clap::ArgMatches, config: &Config) -> Result<(), String> {
+    let exe = env::current_exe().map_err(|err| format!("Error finding the sekey binary: {}", err))?;
+    let socket = socket_path(matches, config)?;
+    let force = matches.is_present("force");
+
+    if matches.is_present("systemd") {
+        for unit in install::install_systemd(&exe, &socket, force)? {
+            println!("Wrote {}", unit.display());
+        }
+        println!("Enable it with: systemctl --user daemon-reload && systemctl --user enable --now {}", install::SYSTEMD_SOCKET_UNIT);
+    } else {
+        let plist = install::install_launchd(&exe, &socket, force)?;
+        println!("Wrote {}", plist.display());
+        println!("Load it with: launchctl load -F {}", plist.display());
+    }
+    println!("Then point ssh at the agent: export SSH_AUTH_SOCK={}", socket.display());
+    Ok(())
+}
+
+fn main() {
     let matches = App::new("SeKey")
                       .version("1.0")
                       .author("Nicolas Trippar <ntrippar@gmail.com>")
                       .about("Use Secure Enclave for SSH Authentication")
+                      .arg(Arg::with_name("config")
+                                .long("config")
+                                .value_name("PATH")
+                                .help("Read the configuration from PATH instead of $SEKEY_HOME/config.toml")
+                                .takes_value(true)
+                                .global(true))
                       .arg(Arg::with_name("generate-keypair")
                                 .long("generate-keypair")
                                 .short("c")
                                 .value_name("LABEL")
-                                .help("Generate a key inside the Secure Enclave")
+                                .help("Generate a key in the backend given by --backend, or the first enabled one")
                                 .takes_value(true))
+                      .arg(Arg::with_name("backend")
+                                .long("backend")
+                                .value_name("BACKEND")
+                                .help("Backend that stores the generated or imported key [default: first enabled one]")
+                                .takes_value(true)
+                                .possible_values(&["keychain", "software"]))
+                      .arg(Arg::with_name("import-key")
+                                .long("import-key")
+                                .value_name("PATH")
+                                .help("Import a private key, PEM or unencrypted OpenSSH format, into a software backend")
+                                .takes_value(true)
+                                .conflicts_with("generate-keypair"))
+                      .arg(Arg::with_name("label")
+                                .long("label")
+                                .value_name("LABEL")
+                                .help("Label of the imported key [default: file name]")
+                                .takes_value(true)
+                                .requires("import-key"))
+                      .arg(Arg::with_name("key-type")
+                                .long("key-type")
+                                .value_name("TYPE")
+                                .help("Type of the generated key, the Secure Enclave only does ecdsa-sha2-nistp256 [default: default_key_type of the config]")
+                                .takes_value(true)
+                                .possible_values(store::KEY_TYPES)
+                                .requires("generate-keypair"))
                       .arg(Arg::with_name("list-keys")
                                 .long("list-keys")
                                 .short("l")
                                 .help("List all keys")
                                 .takes_value(false)
                                 .conflicts_with_all(&["generate-keypair"]))
+                      .arg(Arg::with_name("md5")
+                                .long("md5")
+                                .help("Show MD5 fingerprints instead of SHA256 ones")
+                                .takes_value(false)
+                                .requires("list-keys"))
+                      .arg(Arg::with_name("randomart")
+                                .long("randomart")
+                                .help("Draw the randomart of each key, like ssh-keygen -lv")
+                                .takes_value(false)
+                                .requires("list-keys"))
                           .arg(Arg::with_name("daemon")
                                 .long("daemon")
                                 .help("Run the daemon")
                                 .takes_value(false)
                                 .conflicts_with_all(&["list-keys"]))
+                      .arg(Arg::with_name("socket")
+                                .long("socket")
+                                .value_name("PATH")
+                                .help("Bind the agent to PATH instead of $SEKEY_HOME/ssh-agent.ssh")
+                                .takes_value(true))
+                      .arg(Arg::with_name("foreground")
+                                .long("foreground")
+                                .help("Keep the daemon attached to the terminal")
+                                .takes_value(false)
+                                .requires("daemon"))
+                      .arg(Arg::with_name("kill")
+                                .long("kill")
+                                .short("k")
+                                .help("Kill the running daemon")
+                                .takes_value(false)
+                                .conflicts_with_all(&["daemon"]))
+                      .arg(Arg::with_name("sh")
+                                .short("s")
+                                .help("Print sh style environment commands")
+                                .takes_value(false)
+                                .conflicts_with_all(&["csh", "fish"]))
+                      .arg(Arg::with_name("csh")
+                                .long("csh")
+                                .short("C")
+                                .help("Print csh style environment commands")
+                                .takes_value(false)
+                                .conflicts_with_all(&["sh", "fish"]))
+                      .arg(Arg::with_name("fish")
+                                .long("fish")
+                                .help("Print fish style environment commands")
+                                .takes_value(false)
+                                .conflicts_with_all(&["sh", "csh"]))
                       .arg(Arg::with_name("export-key")
                                 .long("export-key")
                                 .short("e")
-                                .value_name("ID")
-                                .help("export key to OpenSSH Format")
+                                .value_name("KEY")
+                                .help("export key to OpenSSH Format, by label, fingerprint or ID")
                                 .takes_value(true)
                                 .conflicts_with_all(&["list-keys"]))
+                      .arg(Arg::with_name("export-all")
+                                .long("export-all")
+                                .short("L")
+                                .help("Export every key, like ssh-add -L")
+                                .takes_value(false)
+                                .conflicts_with_all(&["list-keys", "export-key"]))
+                      .arg(Arg::with_name("format")
+                                .long("format")
+                                .value_name("FORMAT")
+                                .help("Format of the exported keys")
+                                .takes_value(true)
+                                .possible_values(EXPORT_FORMATS)
+                                .default_value("openssh"))
                       .arg(Arg::with_name("delete-keypair")
                                 .long("delete-keypair")
                                 .short("d")
-                                .value_name("ID")
-                                .help("Deletes the keypair")
+                                .value_name("KEY")
+                                .help("Deletes the keypair, by label, fingerprint or ID")
                                 .takes_value(true)
                                 .conflicts_with_all(&["list-keys"]))
+                      .arg(Arg::with_name("limit-key")
+                                .long("limit-key")
+                                .value_name("RATE")
+                                .help("Limit sign requests for each key, e.g. 10/60s")
+                                .takes_value(true)
+                                .requires("daemon"))
+                      .arg(Arg::with_name("limit-peer")
+                                .long("limit-peer")
+                                .value_name("RATE")
+                                .help("Limit sign requests for each client executable, e.g. 30/m")
+                                .takes_value(true)
+                                .requires("daemon"))
+                      .arg(Arg::with_name("limit-global")
+                                .long("limit-global")
+                                .value_name("RATE")
+                                .help("Limit sign requests for the whole agent, e.g. 100/h")
+                                .takes_value(true)
+                                .requires("daemon"))
+                      .arg(Arg::with_name("limit-hook")
+                                .long("limit-hook")
+                                .value_name("COMMAND")
+                                .help("Command to run when sign requests keep being rate limited")
+                                .takes_value(true)
+                                .requires("daemon"))
+                      .arg(Arg::with_name("limit-hook-threshold")
+                                .long("limit-hook-threshold")
+                                .value_name("COUNT")
+                                .help("Refused requests within a minute before the hook runs [default: 5]")
+                                .takes_value(true)
+                                .requires("limit-hook"))
+                      .subcommand(SubCommand::with_name("install")
+                                .about("Install a service that starts the daemon on login")
+                                .arg(Arg::with_name("systemd")
+                                          .long("systemd")
+                                          .help("Write sekey.socket and sekey.service systemd user units")
+                                          .takes_value(false))
+                                .arg(Arg::with_name("launchd")
+                                          .long("launchd")
+                                          .help("Write the launchd LaunchAgent plist")
+                                          .takes_value(false))
+                                .group(clap::ArgGroup::with_name("service")
+                                          .args(&["systemd", "launchd"])
+                                          .required(true))
+                                .arg(Arg::with_name("socket")
+                                          .long("socket")
+                                          .value_name("PATH")
+                                          .help("Socket the service listens on")
+                                          .takes_value(true))
+                                .arg(Arg::with_name("force")
+                                          .long("force")
+                                          .help("Overwrite existing files")
+                                          .takes_value(false)))
+                      .subcommand(SubCommand::with_name("ctl")
+                                .about("Talk to the running daemon through its control socket")
+                                .arg(Arg::with_name("socket")
+                                          .long("socket")
+                                          .value_name("PATH")
+                                          .help("Agent socket of the daemon")
+                                          .takes_value(true))
+                                .arg(Arg::with_name("json")
+                                          .long("json")
+                                          .help("Print the raw JSON reply")
+                                          .takes_value(false))
+                                .subcommand(SubCommand::with_name("status")
+                                          .about("Show the state of the daemon"))
+                                .subcommand(SubCommand::with_name("identities")
+                                          .about("List loaded keys and where they come from"))
+                                .subcommand(SubCommand::with_name("reload")
+                                          .about("Read the configuration and the policy again"))
+                                .subcommand(SubCommand::with_name("refresh-keys")
+                                          .about("Look for keys added or removed by another process"))
+                                .subcommand(SubCommand::with_name("lock")
+                                          .about("Refuse to list or use keys until unlocked")
+                                          .arg(Arg::with_name("passphrase")
+                                                    .long("passphrase")
+                                                    .help("Ask for a passphrase needed to unlock")
+                                                    .takes_value(false)))
+                                .subcommand(SubCommand::with_name("unlock")
+                                          .about("Unlock the agent")
+                                          .arg(Arg::with_name("passphrase")
+                                                    .long("passphrase")
+                                                    .help("Ask for the passphrase the agent was locked with")
+                                                    .takes_value(false)))
+                                .subcommand(SubCommand::with_name("drop-keys")
+                                          .about("Forget the keys a backend holds in memory")
+                                          .arg(Arg::with_name("backend")
+                                                    .long("backend")
+                                                    .value_name("BACKEND")
+                                                    .help("Backend to drop keys from [default: software]")
+                                                    .takes_value(true)))
+                                .subcommand(SubCommand::with_name("counters")
+                                          .about("Show request counters"))
+                                .subcommand(SubCommand::with_name("events")
+                                          .about("Follow the event stream, one JSON object per line")))
+                      .subcommand(SubCommand::with_name("authorize")
+                                .about("Print or append an authorized_keys line for a key, with restrictions")
+                                .arg(Arg::with_name("key")
+                                          .value_name("KEY")
+                                          .help("Label, fingerprint or ID of the key")
+                                          .required(true))
+                                .arg(Arg::with_name("file")
+                                          .long("file")
+                                          .short("f")
+                                          .value_name("PATH")
+                                          .help("authorized_keys file to append the line to, dropping duplicate lines, instead of printing it")
+                                          .takes_value(true))
+                                .arg(Arg::with_name("force")
+                                          .long("force")
+                                          .help("Replace the lines of the file that grant the key with other options")
+                                          .takes_value(false)
+                                          .requires("file"))
+                                .arg(Arg::with_name("from")
+                                          .long("from")
+                                          .value_name("PATTERNS")
+                                          .help("Comma separated host patterns or CIDR ranges the key may be used from, e.g. 10.0.0.0/8,!10.0.0.1")
+                                          .takes_value(true))
+                                .arg(Arg::with_name("command")
+                                          .long("command")
+                                          .value_name("COMMAND")
+                                          .help("Command run on every connection, whatever the client asks for")
+                                          .takes_value(true))
+                                .arg(Arg::with_name("expiry-time")
+                                          .long("expiry-time")
+                                          .value_name("TIME")
+                                          .help("When the key stops being accepted, e.g. +52w or YYYYMMDD[HHMM[SS]] in UTC")
+                                          .takes_value(true))
+                                .arg(Arg::with_name("restrict")
+                                          .long("restrict")
+                                          .help("Disable forwarding, the pty and ~/.ssh/rc")
+                                          .takes_value(false))
+                                .arg(Arg::with_name("no-port-forwarding")
+                                          .long("no-port-forwarding")
+                                          .takes_value(false))
+                                .arg(Arg::with_name("no-agent-forwarding")
+                                          .long("no-agent-forwarding")
+                                          .takes_value(false))
+                                .arg(Arg::with_name("no-x11-forwarding")
+                                          .long("no-x11-forwarding")
+                                          .takes_value(false))
+                                .arg(Arg::with_name("no-pty")
+                                          .long("no-pty")
+                                          .takes_value(false))
+                                .arg(Arg::with_name("no-user-rc")
+                                          .long("no-user-rc")
+                                          .takes_value(false))
+                                .arg(Arg::with_name("cert-authority")
+                                          .long("cert-authority")
+                                          .help("Trust the certificates the key signs rather than the key itself")
+                                          .takes_value(false))
+                                .arg(Arg::with_name("principals")
+                                          .long("principals")
+                                          .value_name("NAMES")
+                                          .help("Comma separated names certificates signed by the key must carry")
+                                          .takes_value(true)
+                                          .requires("cert-authority")))
+                      .subcommand(SubCommand::with_name("sign")
+                                .about("Sign files like ssh-keygen -Y sign, stdin when no file is given")
+                                .arg(Arg::with_name("file")
+                                          .value_name("FILE")
+                                          .help("File to sign, the signature going to FILE.sig")
+                                          .multiple(true))
+                                .arg(Arg::with_name("key")
+                                          .long("key")
+                                          .short("k")
+                                          .value_name("KEY")
+                                          .help("Label, fingerprint or ID of the signing key")
+                                          .takes_value(true)
+                                          .required(true))
+                                .arg(Arg::with_name("namespace")
+                                          .long("namespace")
+                                          .short("n")
+                                          .value_name("NAMESPACE")
+                                          .help("What the signature is for, e.g. file or git")
+                                          .takes_value(true)
+                                          .required(true))
+                                .arg(Arg::with_name("hash")
+                                          .long("hash")
+                                          .value_name("ALGORITHM")
+                                          .help("Hash of the signed message")
+                                          .takes_value(true)
+                                          .possible_values(HASH_ALGORITHMS)
+                                          .default_value(sshsig::DEFAULT_HASH_ALGORITHM)))
+                      .subcommand(SubCommand::with_name("verify")
+                                .about("Verify a signature like ssh-keygen -Y verify, the message read from stdin when no file is given")
+                                .arg(Arg::with_name("file")
+                                          .value_name("FILE")
+                                          .help("Signed file"))
+                                .arg(Arg::with_name("signature")
+                                          .long("signature")
+                                          .short("s")
+                                          .value_name("PATH")
+                                          .help("Armored SSH signature, e.g. FILE.sig")
+                                          .takes_value(true)
+                                          .required(true))
+                                .arg(Arg::with_name("allowed-signers")
+                                          .long("allowed-signers")
+                                          .short("f")
+                                          .value_name("PATH")
+                                          .help("allowed_signers file listing who may sign with which key")
+                                          .takes_value(true)
+                                          .required(true))
+                                .arg(Arg::with_name("identity")
+                                          .long("identity")
+                                          .short("I")
+                                          .value_name("PRINCIPAL")
+                                          .help("Who the signature should be from")
+                                          .takes_value(true)
+                                          .required(true))
+                                .arg(Arg::with_name("namespace")
+                                          .long("namespace")
+                                          .short("n")
+                                          .value_name("NAMESPACE")
+                                          .help("What the signature should be for")
+                                          .takes_value(true)
+                                          .required(true)))
+                      .subcommand(SubCommand::with_name("cert")
+                                .about("Run an SSH certificate authority with a sekey key")
+                                .subcommand(SubCommand::with_name("sign")
+                                          .about("Issue a certificate for a public key")
+                                          .arg(Arg::with_name("public-key")
+                                                    .value_name("PUBLIC_KEY")
+                                                    .help("OpenSSH public key file to certify")
+                                                    .required(true))
+                                          .arg(Arg::with_name("ca")
+                                                    .long("ca")
+                                                    .value_name("KEY")
+                                                    .help("Label, fingerprint or ID of the sekey key that signs the certificate")
+                                                    .takes_value(true)
+                                                    .required(true))
+                                          .arg(Arg::with_name("key-id")
+                                                    .long("key-id")
+                                                    .short("I")
+                                                    .value_name("KEY_ID")
+                                                    .help("Key identifier, logged by sshd when the certificate is used")
+                                                    .takes_value(true)
+                                                    .required(true))
+                                          .arg(Arg::with_name("principals")
+                                                    .long("principals")
+                                                    .short("n")
+                                                    .value_name("NAMES")
+                                                    .help("Comma separated user or host names the certificate is valid for")
+                                                    .takes_value(true))
+                                          .arg(Arg::with_name("host")
+                                                    .long("host")
+                                                    .help("Issue a host certificate instead of a user certificate")
+                                                    .takes_value(false))
+                                          .arg(Arg::with_name("serial")
+                                                    .long("serial")
+                                                    .short("z")
+                                                    .value_name("SERIAL")
+                                                    .help("Serial number [default: 0]")
+                                                    .takes_value(true))
+                                          .arg(Arg::with_name("valid")
+                                                    .long("valid")
+                                                    .short("V")
+                                                    .value_name("INTERVAL")
+                                                    .help("Validity like ssh-keygen -V, e.g. +52w or -5m:+1d [default: always:forever]")
+                                                    .takes_value(true))
+                                          .arg(Arg::with_name("critical")
+                                                    .long("critical")
+                                                    .value_name("NAME[=VALUE]")
+                                                    .help("Critical option, e.g. force-command=/bin/true or source-address=10.0.0.0/8")
+                                                    .takes_value(true)
+                                                    .multiple(true)
+                                                    .number_of_values(1))
+                                          .arg(Arg::with_name("extension")
+                                                    .long("extension")
+                                                    .value_name("NAME[=VALUE]")
+                                                    .help("Extension added to the certificate, e.g. permit-pty")
+                                                    .takes_value(true)
+                                                    .multiple(true)
+                                                    .number_of_values(1))
+                                          .arg(Arg::with_name("clear-extensions")
+                                                    .long("clear-extensions")
+                                                    .help("Leave out the default permit-* extensions of user certificates")
+                                                    .takes_value(false))
+                                          .arg(Arg::with_name("output")
+                                                    .long("output")
+                                                    .short("o")
+                                                    .value_name("PATH")
+                                                    .help("Where to write the certificate [default: <PUBLIC_KEY>-cert.pub]")
+                                                    .takes_value(true)))
+                                .subcommand(SubCommand::with_name("show")
+                                          .about("Decode certificates like ssh-keygen -L")
+                                          .arg(Arg::with_name("certificate")
+                                                    .value_name("CERTIFICATE")
+                                                    .help("Certificate file, e.g. ~/.ssh/id_ed25519-cert.pub")
+                                                    .required(true)
+                                                    .multiple(true))))
+                      .subcommand(SubCommand::with_name("krl")
+                                .about("Revoke keys and certificates with an OpenSSH key revocation list")
+                                .subcommand(SubCommand::with_name("update")
+                                          .about("Create or update a KRL, signed by the CA key when --ca is given")
+                                          .arg(Arg::with_name("krl")
+                                                    .value_name("KRL")
+                                                    .help("KRL file, for sshd's RevokedKeys")
+                                                    .required(true))
+                                          .arg(Arg::with_name("ca")
+                                                    .long("ca")
+                                                    .value_name("KEY")
+                                                    .help("Label, fingerprint or ID of the sekey CA key the revoked certificates were signed with")
+                                                    .takes_value(true))
+                                          .arg(Arg::with_name("serial")
+                                                    .long("serial")
+                                                    .short("z")
+                                                    .value_name("SERIALS")
+                                                    .help("Revoke certificates by serial, e.g. 42 or 100-199")
+                                                    .takes_value(true)
+                                                    .multiple(true)
+                                                    .number_of_values(1))
+                                          .arg(Arg::with_name("key-id")
+                                                    .long("key-id")
+                                                    .short("I")
+                                                    .value_name("KEY_ID")
+                                                    .help("Revoke certificates by key ID")
+                                                    .takes_value(true)
+                                                    .multiple(true)
+                                                    .number_of_values(1))
+                                          .arg(Arg::with_name("key")
+                                                    .long("key")
+                                                    .value_name("PUBLIC_KEY")
+                                                    .help("Revoke a public key, or a certificate by its serial")
+                                                    .takes_value(true)
+                                                    .multiple(true)
+                                                    .number_of_values(1))
+                                          .arg(Arg::with_name("comment")
+                                                    .long("comment")
+                                                    .value_name("TEXT")
+                                                    .help("Comment stored in the KRL")
+                                                    .takes_value(true)))
+                                .subcommand(SubCommand::with_name("check")
+                                          .about("Check keys or certificates against a KRL like ssh-keygen -Q")
+                                          .arg(Arg::with_name("krl")
+                                                    .value_name("KRL")
+                                                    .required(true))
+                                          .arg(Arg::with_name("key")
+                                                    .value_name("PUBLIC_KEY")
+                                                    .help("Public key or certificate file")
+                                                    .required(true)
+                                                    .multiple(true))))
+                      .subcommand(SubCommand::with_name("config")
+                                .about("Manage the configuration file")
+                                .subcommand(SubCommand::with_name("check")
+                                          .about("Validate the configuration and the policy file")))
                       .get_matches();
 
-    // printing format
-    let format = format::FormatBuilder::new()
-        .column_separator('│')
-        .borders('│')
-        .separators(&[format::LinePosition::Top],
-            format::LineSeparator::new('─', '┬', '┌', '┐'))
-        .separators(&[format::LinePosition::Title],
-            format::LineSeparator::new('─', '┼', '├', '┤'))
-        .separators(&[format::LinePosition::Bottom],
-            format::LineSeparator::new('─', '┴', '└', '┘'))
+    if let Some(config_matches) = matches.subcommand_matches("config") {
+        if config_matches.subcommand_matches("check").is_some() {
+            if let Err(err) = check_config(&matches) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-        .padding(5, 5)
-        .build();
+    let config = match load_config(&matches) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    init_logging(&config);
 
+    let mut store = match KeyStore::from_config(&config) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
 
     // match list-keys
     if matches.is_present("list-keys") {
         let mut table = Table::new();
-        table.set_format(format);
-        table.set_titles(row![bc => "Label", "ID"]);
+        table.set_format(table_format(&config));
+        table.set_titles(row![bc => "Label", "ID", "Fingerprint", "Backend", "Certificates"]);
 
-        let keys = Keychain::get_public_keys();
-        if keys.len() >= 1 {
-            for key in keys{
-                //key.hash
-                table.add_row(row![key.label, hex::encode(key.hash)]);
+        let keys = store.public_keys();
+        if !keys.is_empty() {
+            let now = cert::now();
+            for key in keys.iter() {
+                // one line per certificate of the key, the ones about to expire flagged
+                let certificates: Vec<String> = store.stored_certificates().iter()
+                    .filter(|stored| stored.certificate.public_key().ok().as_ref() == Some(&key.pubkey.key))
+                    .map(|stored| format!("\"{}\" {}", stored.certificate.key_id, certificate_status(&stored.certificate, now, config.certificate_warning())))
+                    .collect();
+                let fingerprint = if matches.is_present("md5") { key.pubkey.key.md5_fingerprint() } else { key.pubkey.key.fingerprint() };
+                table.add_row(row![key.pubkey.label, key.pubkey.id, fingerprint, key.backend, certificates.join("\n")]);
             }
             table.printstd();
+            if matches.is_present("randomart") {
+                for key in keys.iter() {
+                    println!("\n{} {}\n{}", key.pubkey.label, key.pubkey.key.fingerprint(), key.pubkey.key.randomart());
+                }
+            }
         } else {
             println!("No keys stored");
         }
     }
 
     // match export-key
-    if let Some(key_id) = matches.value_of("export-key") {
-        if let Ok(key_id) = hex::decode(key_id) {
-            let key = Keychain::get_public_key(key_id);
-            match key {
-                Ok(key) => {
-                    let key = EcdsaSha2Nistp256::write(key.key);
-                    println!("{} {}", CURVE_TYPE, base64::encode(key.as_slice()))
-                }
-                Err(_) => eprintln!("Invalid key ID"),
+    let format = matches.value_of("format").and_then(export::Format::from_name).unwrap_or(export::Format::OpenSsh);
+    if let Some(selector) = matches.value_of("export-key") {
+        let exported = store.select(selector)
+            .and_then(|key| export::export(&key.pubkey, format).map_err(String::from));
+        match exported {
+            Ok(exported) => println!("{}", exported),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
             }
-
         }
-        
     }
 
-    if let Some(key_id) = matches.value_of("delete-keypair") {
-        if let Ok(key_id_) = hex::decode(key_id) {
-            let key = Keychain::delete_keypair(key_id_);
-            match key {
-                Ok(_) => println!("Key {} successfully deleted", key_id),
-                Err(_) => eprintln!("Error deleting key"),
+    if matches.is_present("export-all") {
+        // keys the format can't hold are left out rather than failing the others
+        let stored = store.public_keys();
+        let stored_count = stored.len();
+        let keys: Vec<store::PubKey> = stored.into_iter()
+            .filter(|key| {
+                let supported = format.supports(&key.pubkey.key);
+                if !supported {
+                    eprintln!("Skipping {}: {} keys can't be exported in that format", key.pubkey.label, key.key_type());
+                }
+                supported
+            })
+            .map(|key| key.pubkey)
+            .collect();
+        match export::export_all(&keys, format) {
+            Ok(ref exported) if !keys.is_empty() => println!("{}", exported),
+            Ok(_) if stored_count == 0 => eprintln!("No keys stored"),
+            Ok(_) => {
+                eprintln!("No stored key can be exported in that format");
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
             }
+        }
+    }
 
+    if let Some(selector) = matches.value_of("delete-keypair") {
+        let deleted = store.select(selector)
+            .and_then(|key| store.delete(&key.pubkey.id).map(|_| key).map_err(|err| format!("Error deleting key: {}", err)));
+        match deleted {
+            Ok(key) => {
+                println!("Key {} ({}) successfully deleted", key.pubkey.label, key.pubkey.id);
+                notify_agent(&matches, &config);
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
         }
-        
     }
 
 
+    if let Some(path) = matches.value_of("import-key") {
+        let label = matches.value_of("label").map(String::from).unwrap_or_else(|| {
+            Path::new(path).file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+        });
+        let imported = fs::read(path)
+            .map_err(|err| format!("Error reading {}: {}", path, err))
+            .and_then(|contents| store.import(matches.value_of("backend"), &label, &contents).map_err(String::from));
+        match imported {
+            Ok(backend) => {
+                println!("Key {} successfully imported in the {} backend", label, backend);
+                notify_agent(&matches, &config);
+            }
+            Err(err) => {
+                eprintln!("Error importing key: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+
     if let Some(label) = matches.value_of("generate-keypair") {
-        let key = Keychain::generate_keypair(label.to_string());
+        let key = store.generate(matches.value_of("backend"), label, matches.value_of("key-type").unwrap_or(&config.default_key_type));
         match key {
-            Ok(_) => {
-                println!("Keypair {} successfully generated", label)
+            Ok(backend) => {
+                println!("Keypair {} successfully generated in the {} backend", label, backend);
+                notify_agent(&matches, &config);
             },
-            Err(_) => eprintln!("Error generating key"),
+            Err(err) => eprintln!("Error generating key: {}", err),
         }
         
     }
@@ -160,31 +1329,87 @@ fn main() {
     //generate_keypair
     // run the daemon!
     if matches.is_present("daemon") {
-        match env::home_dir() {
-            Some(path) => {
-                match create_home_path(path.clone()){
-                    Ok(_) => {
-                        let pipe = format!("{}{}{}", path.display(), SEKEY_HOME_FOLDER, SSH_AGENT_PIPE);
-                        let pipe = Path::new(pipe.as_str());
-                        if fs::metadata(&pipe).is_ok() {
-                            if let Ok(_) = fs::remove_file(&pipe){
-                                println!("Pipe deleted");
-
-                            }
-
-                        }
-                        println!("binding to {}", pipe.display());
-                        let listener = UnixListener::bind(pipe);
-                        let handler = Handler::new();
-                        ssh_agent::Agent::run(handler, listener.unwrap());
-                    }
-                    Err(_) => eprintln!("Error creating home path"),
+        if let Err(err) = run_daemon(&matches) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("install") {
+        if let Err(err) = install_service(matches, &config) {
+            eprintln!("{}", err);
+        }
+    }
+
+    if let Some(authorize_matches) = matches.subcommand_matches("authorize") {
+        if let Err(err) = authorize_key(authorize_matches, &mut store) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    if let Some(sign_matches) = matches.subcommand_matches("sign") {
+        if let Err(err) = sign_messages(sign_matches, &mut store) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    if let Some(verify_matches) = matches.subcommand_matches("verify") {
+        if let Err(err) = verify_message(verify_matches) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    if let Some(cert_matches) = matches.subcommand_matches("cert") {
+        if let Some(sign_matches) = cert_matches.subcommand_matches("sign") {
+            if let Err(err) = sign_certificate(sign_matches, &mut store) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        if let Some(show_matches) = cert_matches.subcommand_matches("show") {
+            for path in show_matches.values_of("certificate").into_iter().flatten() {
+                if let Err(err) = show_certificate(&PathBuf::from(path), &config) {
+                    eprintln!("{}", err);
+                    process::exit(1);
                 }
-            },
-            None => eprintln!("Impossible to get home dir!"),
+            }
         }
-        
+    }
+
+    if let Some(krl_matches) = matches.subcommand_matches("krl") {
+        if let Some(update_matches) = krl_matches.subcommand_matches("update") {
+            if let Err(err) = update_krl(update_matches, &mut store) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        if let Some(check_matches) = krl_matches.subcommand_matches("check") {
+            match check_krl(check_matches) {
+                Ok(true) => {}
+                Ok(false) => process::exit(1),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+    }
 
+    if let Some(matches) = matches.subcommand_matches("ctl") {
+        if let Err(err) = run_ctl(matches, &config) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    if matches.is_present("kill") {
+        if let Err(err) = kill_daemon(&matches, &config) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
     }
 
 }
@@ -0,0 +1,604 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use byteorder::{BigEndian, WriteBytesExt};
+use openssl::rand::rand_bytes;
+
+use key::{KeyId, PublicKey};
+use store::KeyStore;
+use wire::{write_string, Reader};
+
+pub const CERT_SUFFIX: &str = "-cert-v01@openssh.com";
+
+// key types OpenSSH issues certificates for
+pub static CERT_KEY_TYPES: &[&str] = &[
+	"ssh-rsa",
+	"ssh-dss",
+	"ecdsa-sha2-nistp256",
+	"ecdsa-sha2-nistp384",
+	"ecdsa-sha2-nistp521",
+	"ssh-ed25519",
+	"sk-ecdsa-sha2-nistp256@openssh.com",
+	"sk-ssh-ed25519@openssh.com",
+];
+
+// critical options sshd knows about, it refuses certificates carrying any other
+pub static CRITICAL_OPTIONS: &[&str] = &["force-command", "source-address", "verify-required"];
+
+// what ssh-keygen grants user certificates unless told otherwise
+pub static DEFAULT_EXTENSIONS: &[&str] = &[
+	"permit-X11-forwarding",
+	"permit-agent-forwarding",
+	"permit-port-forwarding",
+	"permit-pty",
+	"permit-user-rc",
+];
+
+// valid_before of a certificate that never expires
+pub const FOREVER: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CertType {
+	User,
+	Host,
+}
+
+impl CertType {
+	fn code(&self) -> u32 {
+		match *self {
+			CertType::User => 1,
+			CertType::Host => 2,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			CertType::User => "user",
+			CertType::Host => "host",
+		}
+	}
+}
+
+// strings making up the public key of each type: e and n for RSA, curve and point for
+// ECDSA, and so on
+fn key_fields(key_type: &str) -> usize {
+	match key_type {
+		"ssh-rsa" => 2,
+		"ssh-dss" => 4,
+		"ssh-ed25519" => 1,
+		"sk-ssh-ed25519@openssh.com" => 2,
+		"sk-ecdsa-sha2-nistp256@openssh.com" => 3,
+		_ => 2,
+	}
+}
+
+// whether `blob` is a certificate rather than a plain key
+pub fn is_certificate(blob: &[u8]) -> bool {
+	Reader::new(blob).read_string()
+		.map(|key_type| key_type.ends_with(CERT_SUFFIX.as_bytes()))
+		.unwrap_or(false)
+}
+
+// certificate type for a key type, e.g. ecdsa-sha2-nistp256-cert-v01@openssh.com
+pub fn cert_key_type(key_type: &str) -> Result<String, &'static str> {
+	if !CERT_KEY_TYPES.contains(&key_type) {
+		return Err("Unsupported key type for a certificate");
+	}
+	Ok(match key_type.find("@openssh.com") {
+		Some(position) => format!("{}{}", &key_type[..position], CERT_SUFFIX),
+		None => format!("{}{}", key_type, CERT_SUFFIX),
+	})
+}
+
+// OpenSSH certificate, see PROTOCOL.certkeys in the OpenSSH sources. The subject key is
+// kept as its wire encoding without the leading key type, which is exactly what goes
+// into the certificate whatever the type of the key is.
+#[derive(Debug, Clone)]
+pub struct Certificate {
+	pub key_type: String,
+	pub nonce: Vec<u8>,
+	pub public_key: Vec<u8>,
+	pub serial: u64,
+	pub cert_type: CertType,
+	pub key_id: String,
+	pub principals: Vec<String>,
+	pub valid_after: u64,
+	pub valid_before: u64,
+	pub critical_options: BTreeMap<String, String>,
+	pub extensions: BTreeMap<String, String>,
+	pub signature_key: Vec<u8>,
+	pub signature: Vec<u8>,
+}
+
+impl Certificate {
+
+	// an unsigned user certificate for `key`, valid forever with the default extensions
+	pub fn for_key(key: &[u8]) -> Result<Certificate, &'static str> {
+		let mut reader = Reader::new(key);
+		let key_type = reader.read_string().map_err(|_| "Invalid public key")?;
+		let key_type = String::from_utf8(key_type.to_vec()).map_err(|_| "Invalid public key")?;
+		cert_key_type(&key_type)?;
+
+		let mut nonce = vec![0; 32];
+		rand_bytes(&mut nonce).map_err(|_| "Error generating nonce")?;
+
+		Ok(Certificate {
+			key_type: key_type,
+			nonce: nonce,
+			public_key: key[key.len() - reader.remaining()..].to_vec(),
+			serial: 0,
+			cert_type: CertType::User,
+			key_id: String::new(),
+			principals: vec![],
+			valid_after: 0,
+			valid_before: FOREVER,
+			critical_options: BTreeMap::new(),
+			extensions: DEFAULT_EXTENSIONS.iter().map(|name| (name.to_string(), String::new())).collect(),
+			signature_key: vec![],
+			signature: vec![],
+		})
+	}
+
+	pub fn parse(blob: &[u8]) -> Result<Certificate, &'static str> {
+		Certificate::read(blob).map_err(|_| "Invalid certificate")
+	}
+
+	fn read(blob: &[u8]) -> Result<Certificate, &'static str> {
+		let mut reader = Reader::new(blob);
+		let cert_type = reader.read_string()?;
+		let key_type = CERT_KEY_TYPES.iter()
+			.find(|key_type| cert_key_type(key_type).ok().is_some_and(|name| name.as_bytes() == cert_type))
+			.ok_or("Unsupported certificate type")?;
+		let nonce = reader.read_string()?.to_vec();
+
+		let start = blob.len() - reader.remaining();
+		for _ in 0..key_fields(key_type) {
+			reader.read_string()?;
+		}
+		let public_key = blob[start..blob.len() - reader.remaining()].to_vec();
+
+		let serial = reader.read_u64()?;
+		let cert_type = match reader.read_u32()? {
+			1 => CertType::User,
+			2 => CertType::Host,
+			_ => return Err("Unknown certificate type"),
+		};
+		let key_id = String::from_utf8_lossy(reader.read_string()?).into_owned();
+
+		let mut principals = Reader::new(reader.read_string()?);
+		let mut principal_list = vec![];
+		while principals.remaining() > 0 {
+			principal_list.push(String::from_utf8_lossy(principals.read_string()?).into_owned());
+		}
+
+		let valid_after = reader.read_u64()?;
+		let valid_before = reader.read_u64()?;
+		let critical_options = read_options(reader.read_string()?)?;
+		let extensions = read_options(reader.read_string()?)?;
+		// reserved
+		reader.read_string()?;
+		let signature_key = reader.read_string()?.to_vec();
+		let signature = reader.read_string()?.to_vec();
+		reader.finish()?;
+
+		Ok(Certificate {
+			key_type: key_type.to_string(),
+			nonce: nonce,
+			public_key: public_key,
+			serial: serial,
+			cert_type: cert_type,
+			key_id: key_id,
+			principals: principal_list,
+			valid_after: valid_after,
+			valid_before: valid_before,
+			critical_options: critical_options,
+			extensions: extensions,
+			signature_key: signature_key,
+			signature: signature,
+		})
+	}
+
+	pub fn is_valid_at(&self, time: u64) -> bool {
+		self.valid_after <= time && time < self.valid_before
+	}
+
+	// validity the way ssh-keygen -L prints it
+	pub fn validity(&self) -> String {
+		match (self.valid_after, self.valid_before) {
+			(0, FOREVER) => String::from("forever"),
+			(0, before) => format!("before {}", format_time(before)),
+			(after, FOREVER) => format!("after {}", format_time(after)),
+			(after, before) => format!("from {} to {}", format_time(after), format_time(before)),
+		}
+	}
+
+	// algorithm the CA signed with, the first field of the signature
+	pub fn signature_algorithm(&self) -> String {
+		blob_key_type(&self.signature)
+	}
+
+	// the subject key as a regular public key blob
+	pub fn subject_key(&self) -> Vec<u8> {
+		let mut key = vec![];
+		write_string(&mut key, self.key_type.as_bytes()).unwrap();
+		key.extend_from_slice(&self.public_key);
+		key
+	}
+
+	// the subject key, as long as it's a type we know
+	pub fn public_key(&self) -> Result<PublicKey, &'static str> {
+		PublicKey::from_blob(&self.subject_key())
+	}
+
+	// everything the CA signs: the whole certificate up to the signature
+	fn signed_part(&self) -> io::Result<Vec<u8>> {
+		let mut data = vec![];
+		write_string(&mut data, cert_key_type(&self.key_type).unwrap_or_default().as_bytes())?;
+		write_string(&mut data, &self.nonce)?;
+		data.extend_from_slice(&self.public_key);
+		data.write_u64::<BigEndian>(self.serial)?;
+		data.write_u32::<BigEndian>(self.cert_type.code())?;
+		write_string(&mut data, self.key_id.as_bytes())?;
+
+		let mut principals = vec![];
+		for principal in &self.principals {
+			write_string(&mut principals, principal.as_bytes())?;
+		}
+		write_string(&mut data, &principals)?;
+
+		data.write_u64::<BigEndian>(self.valid_after)?;
+		data.write_u64::<BigEndian>(self.valid_before)?;
+		write_string(&mut data, &write_options(&self.critical_options)?)?;
+		write_string(&mut data, &write_options(&self.extensions)?)?;
+		// reserved
+		write_string(&mut data, &[])?;
+		write_string(&mut data, &self.signature_key)?;
+		Ok(data)
+	}
+
+	// signs the certificate with the CA key `ca_id` from the store
+	pub fn sign(&mut self, store: &mut KeyStore, ca_id: &KeyId) -> Result<(), &'static str> {
+		let ca = store.get_public_key(ca_id).ok_or("CA key not found")?;
+		self.signature_key = ca.blob();
+
+		let data = self.signed_part().map_err(|_| "Error encoding certificate")?;
+		self.signature = store.ssh_signature(ca_id, &data)?.to_blob();
+		Ok(())
+	}
+
+	pub fn to_blob(&self) -> Vec<u8> {
+		let mut blob = self.signed_part().unwrap_or_default();
+		write_string(&mut blob, &self.signature).unwrap();
+		blob
+	}
+
+	// the certificate as a line for a -cert.pub file
+	pub fn to_openssh(&self, comment: &str) -> String {
+		let line = format!("{} {}", cert_key_type(&self.key_type).unwrap_or_default(), base64::encode(&self.to_blob()));
+		if comment.is_empty() {
+			line
+		} else {
+			format!("{} {}", line, comment)
+		}
+	}
+}
+
+// options and extensions are sorted by name, which BTreeMap does for us. A value is
+// wrapped in a string of its own, flags have no value at all.
+fn write_options(options: &BTreeMap<String, String>) -> io::Result<Vec<u8>> {
+	let mut data = vec![];
+	for (name, value) in options {
+		write_string(&mut data, name.as_bytes())?;
+		if value.is_empty() {
+			write_string(&mut data, &[])?;
+		} else {
+			let mut wrapped = vec![];
+			write_string(&mut wrapped, value.as_bytes())?;
+			write_string(&mut data, &wrapped)?;
+		}
+	}
+	Ok(data)
+}
+
+fn read_options(data: &[u8]) -> Result<BTreeMap<String, String>, &'static str> {
+	let mut options = BTreeMap::new();
+	let mut reader = Reader::new(data);
+	while reader.remaining() > 0 {
+		let name = String::from_utf8_lossy(reader.read_string()?).into_owned();
+		let wrapped = reader.read_string()?;
+		let value = if wrapped.is_empty() {
+			String::new()
+		} else {
+			String::from_utf8_lossy(Reader::new(wrapped).read_string()?).into_owned()
+		};
+		options.insert(name, value);
+	}
+	Ok(options)
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredCert {
+	pub certificate: Certificate,
+	pub blob: Vec<u8>,
+	pub comment: String,
+	// certificates from ssh-add only live in memory
+	pub path: Option<PathBuf>,
+}
+
+// certificates for our keys: `-cert.pub` files in `$SEKEY_HOME/certs`, plus the ones
+// ssh-add hands to the agent
+pub struct CertStore {
+	dir: Option<PathBuf>,
+	certs: Vec<StoredCert>,
+}
+
+impl CertStore {
+
+	pub const CERTS_FOLDER: &str = "certs";
+
+	pub fn new(dir: PathBuf) -> Self {
+		let mut store = CertStore { dir: Some(dir), certs: vec![] };
+		store.load();
+		store
+	}
+
+	// a store without a folder, only holding what ssh-add hands over
+	pub fn empty() -> Self {
+		CertStore { dir: None, certs: vec![] }
+	}
+
+	// unreadable files are skipped, like broken software keys
+	fn load(&mut self) {
+		let entries = match self.dir.as_ref().map(fs::read_dir) {
+			Some(Ok(entries)) => entries,
+			_ => return,
+		};
+		for entry in entries.filter_map(|entry| entry.ok()) {
+			let path = entry.path();
+			if path.extension().is_none_or(|extension| extension != "pub") {
+				continue;
+			}
+			let loaded = fs::read_to_string(&path).ok()
+				.and_then(|line| parse_public_key(&line).ok())
+				.and_then(|(blob, comment)| Certificate::parse(&blob).ok().map(|cert| (cert, blob, comment)));
+			match loaded {
+				Some((certificate, blob, comment)) => self.insert(StoredCert {
+					certificate: certificate,
+					blob: blob,
+					comment: comment,
+					path: Some(path),
+				}),
+				None => warn!("Skipping unreadable certificate {}", path.display()),
+			}
+		}
+	}
+
+	fn insert(&mut self, cert: StoredCert) {
+		if !self.certs.iter().any(|existing| existing.blob == cert.blob) {
+			self.certs.push(cert);
+		}
+	}
+
+	// `blob` is the certificate as ssh-add sent it, which is what it asks to remove later
+	// and may not be byte for byte what we would encode
+	pub fn add(&mut self, certificate: Certificate, blob: Vec<u8>, comment: String) {
+		self.insert(StoredCert { certificate: certificate, blob: blob, comment: comment, path: None });
+	}
+
+	pub fn remove(&mut self, blob: &[u8]) -> Result<(), &'static str> {
+		let before = self.certs.len();
+		self.certs.retain(|cert| cert.blob.as_slice() != blob || cert.path.is_some());
+		if self.certs.len() == before {
+			return Err("Only certificates added with ssh-add can be removed");
+		}
+		Ok(())
+	}
+
+	pub fn remove_all(&mut self) {
+		self.certs.retain(|cert| cert.path.is_some());
+	}
+
+	// certificates from ssh-add stay, like keys
+	pub fn refresh(&mut self) {
+		self.certs.retain(|cert| cert.path.is_none());
+		self.load();
+	}
+
+	// expired certificates and the ones not valid yet are hidden
+	pub fn valid(&self, time: u64) -> Vec<&StoredCert> {
+		self.certs.iter().filter(|cert| cert.certificate.is_valid_at(time)).collect()
+	}
+
+	pub fn all(&self) -> &[StoredCert] {
+		&self.certs
+	}
+
+	pub fn find(&self, blob: &[u8], time: u64) -> Option<&StoredCert> {
+		self.valid(time).into_iter().find(|cert| cert.blob.as_slice() == blob)
+	}
+}
+
+// a public key line as found in .pub files: key type, base64 blob and an optional comment
+pub fn parse_public_key(line: &str) -> Result<(Vec<u8>, String), &'static str> {
+	let mut fields = line.trim().splitn(3, char::is_whitespace);
+	let key_type = fields.next().ok_or("Empty public key")?;
+	let blob = fields.next().ok_or("Public key is missing its key data")?;
+	let comment = fields.next().unwrap_or("").trim().to_string();
+	let blob = base64::decode(blob).map_err(|_| "Invalid base64 in public key")?;
+
+	let declared = Reader::new(&blob).read_string().map_err(|_| "Invalid public key")?;
+	if declared != key_type.as_bytes() {
+		return Err("Key type doesn't match the key data");
+	}
+	Ok((blob, comment))
+}
+
+// short name of a key type, e.g. ECDSA for ecdsa-sha2-nistp256
+pub fn key_type_name(key_type: &str) -> &'static str {
+	match key_type {
+		"ssh-rsa" => "RSA",
+		"ssh-dss" => "DSA",
+		"ssh-ed25519" => "ED25519",
+		"sk-ecdsa-sha2-nistp256@openssh.com" => "ECDSA-SK",
+		"sk-ssh-ed25519@openssh.com" => "ED25519-SK",
+		key_type if key_type.starts_with("ecdsa-sha2-") => "ECDSA",
+		_ => "UNKNOWN",
+	}
+}
+
+// key type a key blob starts with
+pub fn blob_key_type(blob: &[u8]) -> String {
+	Reader::new(blob).read_string()
+		.map(|key_type| String::from_utf8_lossy(key_type).into_owned())
+		.unwrap_or_default()
+}
+
+// UTC, as YYYY-MM-DDTHH:MM:SS
+pub fn format_time(time: u64) -> String {
+	let (year, month, day) = civil_from_days(time / 86400);
+	let seconds = time % 86400;
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// the largest unit or two, e.g. "3d 4h" or "12m"
+pub fn format_duration(seconds: u64) -> String {
+	let units = [("w", 7 * 86400), ("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+	let mut parts = vec![];
+	let mut left = seconds;
+	for &(name, size) in units.iter() {
+		if left >= size && parts.len() < 2 {
+			parts.push(format!("{}{}", left / size, name));
+			left %= size;
+		} else if !parts.is_empty() {
+			break;
+		}
+	}
+	if parts.is_empty() {
+		return String::from("0s");
+	}
+	parts.join(" ")
+}
+
+pub fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH)
+		.map(|time| time.as_secs())
+		.unwrap_or(0)
+}
+
+// validity interval like ssh-keygen -V: "<from>:<to>" or just "<to>", which starts now.
+// Each end is "always"/"forever", relative to now ("+52w", "-5m") or an absolute UTC
+// date "YYYYMMDD", "YYYYMMDDHHMM" or "YYYYMMDDHHMMSS".
+pub fn parse_validity(interval: &str, now: u64) -> Result<(u64, u64), String> {
+	let (from, to) = match interval.find(':') {
+		Some(position) => (&interval[..position], &interval[position + 1..]),
+		None => ("", interval),
+	};
+	let valid_after = if from.is_empty() { now } else { parse_time(from, now)? };
+	let valid_before = parse_time(to, now)?;
+	if valid_before <= valid_after {
+		return Err(String::from("Certificate would expire before it becomes valid"));
+	}
+	Ok((valid_after, valid_before))
+}
+
+// one end of a validity interval, see parse_validity
+pub fn parse_time(time: &str, now: u64) -> Result<u64, String> {
+	let invalid = || format!("Invalid time \"{}\"", time);
+	match time {
+		"always" => return Ok(0),
+		"forever" => return Ok(FOREVER),
+		"now" => return Ok(now),
+		_ => {}
+	}
+
+	if time.starts_with('+') || time.starts_with('-') {
+		let seconds = parse_duration(&time[1..]).ok_or_else(invalid)?;
+		return Ok(if time.starts_with('+') { now.saturating_add(seconds) } else { now.saturating_sub(seconds) });
+	}
+
+	if !time.bytes().all(|c| c.is_ascii_digit()) || ![8, 12, 14].contains(&time.len()) {
+		return Err(invalid());
+	}
+	let field = |start: usize, end: usize| time.get(start..end).and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+	let (year, month, day) = (field(0, 4), field(4, 6), field(6, 8));
+	let (hour, minute, second) = (field(8, 10), field(10, 12), field(12, 14));
+	if year < 1970 || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+		return Err(invalid());
+	}
+	if hour > 23 || minute > 59 || second > 59 {
+		return Err(invalid());
+	}
+	Ok(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+// "90", "30s", "5m", "12h", "7d" or "52w"
+pub fn parse_duration(duration: &str) -> Option<u64> {
+	let (amount, unit) = duration.split_at(duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len()));
+	let amount = amount.parse::<u64>().ok()?;
+	let unit = match unit {
+		"" | "s" => 1,
+		"m" => 60,
+		"h" => 60 * 60,
+		"d" => 24 * 60 * 60,
+		"w" => 7 * 24 * 60 * 60,
+		_ => return None,
+	};
+	amount.checked_mul(unit)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+	match month {
+		2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+		2 => 28,
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+}
+
+// days since 1970-01-01, from Howard Hinnant's date algorithms
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year / 400;
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146097 + day_of_era - 719468
+}
+
+// inverse of days_from_civil
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+	let days = days + 719468;
+	let era = days / 146097;
+	let day_of_era = days - era * 146097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+	let year = year_of_era + era * 400;
+	(if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_times() {
+		assert_eq!(parse_time("19700101", 0), Ok(0));
+		assert_eq!(parse_time("20240229", 0), Ok(1709164800));
+		assert_eq!(parse_time("20000229235959", 0), Ok(951868799));
+		assert_eq!(parse_time("20250630123015", 0), Ok(1751286615));
+	}
+
+	#[test]
+	fn rejects_days_past_the_end_of_the_month() {
+		for time in &["20250231", "20250229", "21000229", "20240431", "20241131", "20240132", "20240100", "20241301", "202401011260"] {
+			assert!(parse_time(time, 0).is_err(), "{} was accepted", time);
+		}
+	}
+}
@@ -0,0 +1,268 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use cert;
+use control;
+use events;
+use ecdsa::CURVE_TYPE;
+use home;
+use policy::Policy;
+use store;
+
+pub static PRIVATE_KEY_NAME: &str = "com.sekey.priv";
+pub static PUBLIC_KEY_NAME: &str = "com.sekey.pub";
+pub static SIGN_PROMPT: &str = "Authenticate to Sign Data";
+
+pub static CONFIG_FILE: &str = "config.toml";
+static POLICY_FILE: &str = "policy.toml";
+static AUDIT_LOG: &str = "audit.log";
+
+// placeholders accepted in `comment_template`
+pub static COMMENT_PLACEHOLDERS: &[&str] = &["{label}", "{id}", "{type}", "{backend}"];
+
+static LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+// Contents of `$SEKEY_HOME/config.toml`. Every field is optional, missing ones take the
+// defaults below, and paths may start with `~/`. Flags given on the command line win
+// over the file.
+//
+//     backends = ["keychain", "software"]   # where keys live, in listing order
+//     default_key_type = "ecdsa-sha2-nistp256"
+//     comment_template = "{type}"           # {label}, {id}, {type} and {backend}
+//     prompt = "Authenticate to Sign Data"  # Touch ID prompt when signing
+//     policy_file = "~/.sekey/policy.toml"  # rate limits, see policy.rs
+//     allow_rsa_sha1 = false                # plain ssh-rsa signatures, for very old servers
+//     verify_signatures = false             # check every signature before it goes out
+//
+//     [socket]
+//     agent = "~/.sekey/ssh-agent.ssh"
+//     control = "~/.sekey/ssh-agent.ssh.ctl"  # defaults to the agent socket + ".ctl"
+//     events = "~/.sekey/ssh-agent.ssh.events" # defaults to the agent socket + ".events"
+//
+//     [keychain]
+//     private_tag = "com.sekey.priv"
+//     public_tag = "com.sekey.pub"
+//
+//     [logging]
+//     level = "warn"                        # overridden by RUST_LOG
+//     audit = true
+//     audit_file = "~/.sekey/audit.log"
+//
+//     [list]
+//     table_format = "box"                  # or "plain"
+//     certificate_warning = "7d"            # flag certificates expiring this soon
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub backends: Vec<String>,
+	pub default_key_type: String,
+	pub comment_template: String,
+	pub prompt: String,
+	pub policy_file: Option<String>,
+	pub allow_rsa_sha1: bool,
+	pub verify_signatures: bool,
+	pub socket: SocketConfig,
+	pub keychain: KeychainConfig,
+	pub logging: LoggingConfig,
+	pub list: ListConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+	pub agent: Option<String>,
+	pub control: Option<String>,
+	pub events: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeychainConfig {
+	pub private_tag: String,
+	pub public_tag: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+	pub level: String,
+	pub audit: bool,
+	pub audit_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListConfig {
+	pub table_format: String,
+	pub certificate_warning: String,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			backends: store::default_backends(),
+			default_key_type: String::from(CURVE_TYPE),
+			comment_template: String::from("{type}"),
+			prompt: String::from(SIGN_PROMPT),
+			policy_file: None,
+			allow_rsa_sha1: false,
+			verify_signatures: false,
+			socket: SocketConfig::default(),
+			keychain: KeychainConfig::default(),
+			logging: LoggingConfig::default(),
+			list: ListConfig::default(),
+		}
+	}
+}
+
+impl Default for KeychainConfig {
+	fn default() -> Self {
+		Self {
+			private_tag: String::from(PRIVATE_KEY_NAME),
+			public_tag: String::from(PUBLIC_KEY_NAME),
+		}
+	}
+}
+
+impl Default for LoggingConfig {
+	fn default() -> Self {
+		Self {
+			level: String::from("warn"),
+			audit: true,
+			audit_file: None,
+		}
+	}
+}
+
+impl Default for ListConfig {
+	fn default() -> Self {
+		Self {
+			table_format: String::from("box"),
+			certificate_warning: String::from("7d"),
+		}
+	}
+}
+
+impl Config {
+
+	pub fn default_path() -> Result<PathBuf, &'static str> {
+		Ok(home::sekey_home()?.join(CONFIG_FILE))
+	}
+
+	// a missing file is not an error, it just means the defaults
+	pub fn load(path: &Path) -> Result<Config, String> {
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+			Err(err) => return Err(format!("Error reading {}: {}", path.display(), err)),
+		};
+		Config::parse(&contents).map_err(|err| format!("{}: {}", path.display(), err))
+	}
+
+	pub fn parse(contents: &str) -> Result<Config, String> {
+		toml::from_str(contents).map_err(|err| err.to_string())
+	}
+
+	// everything wrong with the configuration, including the policy file it points to
+	pub fn check(&self) -> Vec<String> {
+		let mut problems = vec![];
+
+		if self.backends.is_empty() {
+			problems.push(String::from("backends: at least one backend must be enabled"));
+		}
+		for backend in &self.backends {
+			if !store::BACKENDS.contains(&backend.as_str()) {
+				problems.push(format!("backends: unknown backend \"{}\", expected one of {}", backend, store::BACKENDS.join(", ")));
+			} else if !store::is_available(backend) {
+				problems.push(format!("backends: \"{}\" is not available on this platform", backend));
+			}
+		}
+		if !store::KEY_TYPES.contains(&self.default_key_type.as_str()) {
+			problems.push(format!("default_key_type: unsupported key type \"{}\"", self.default_key_type));
+		}
+
+		let mut template = self.comment_template.clone();
+		for placeholder in COMMENT_PLACEHOLDERS {
+			template = template.replace(placeholder, "");
+		}
+		if template.contains('{') || template.contains('}') {
+			problems.push(format!("comment_template: unknown placeholder, expected {}", COMMENT_PLACEHOLDERS.join(", ")));
+		}
+
+		if self.keychain.private_tag.is_empty() || self.keychain.public_tag.is_empty() {
+			problems.push(String::from("keychain: tags can't be empty"));
+		}
+		if !LOG_LEVELS.contains(&self.logging.level.as_str()) {
+			problems.push(format!("logging.level: expected one of {}", LOG_LEVELS.join(", ")));
+		}
+		if self.list.table_format != "box" && self.list.table_format != "plain" {
+			problems.push(String::from("list.table_format: expected \"box\" or \"plain\""));
+		}
+		if cert::parse_duration(&self.list.certificate_warning).is_none() {
+			problems.push(String::from("list.certificate_warning: expected a duration like \"7d\" or \"12h\""));
+		}
+
+		match self.policy_path() {
+			Ok(path) => match Policy::load(&path) {
+				Ok(policy) => problems.extend(policy.check()),
+				Err(err) => problems.push(err),
+			},
+			Err(err) => problems.push(String::from(err)),
+		}
+		problems
+	}
+
+	pub fn agent_socket(&self) -> Result<PathBuf, &'static str> {
+		match self.socket.agent {
+			Some(ref socket) => Ok(home::expand(socket)),
+			None => home::default_socket(),
+		}
+	}
+
+	pub fn control_socket(&self, agent_socket: &Path) -> PathBuf {
+		match self.socket.control {
+			Some(ref socket) => home::expand(socket),
+			None => control::control_path(agent_socket),
+		}
+	}
+
+	pub fn events_socket(&self, agent_socket: &Path) -> PathBuf {
+		match self.socket.events {
+			Some(ref socket) => home::expand(socket),
+			None => events::events_path(agent_socket),
+		}
+	}
+
+	pub fn policy_path(&self) -> Result<PathBuf, &'static str> {
+		match self.policy_file {
+			Some(ref policy) => Ok(home::expand(policy)),
+			None => Ok(home::sekey_home()?.join(POLICY_FILE)),
+		}
+	}
+
+	pub fn audit_path(&self) -> Result<Option<PathBuf>, &'static str> {
+		if !self.logging.audit {
+			return Ok(None);
+		}
+		match self.logging.audit_file {
+			Some(ref audit) => Ok(Some(home::expand(audit))),
+			None => Ok(Some(home::sekey_home()?.join(AUDIT_LOG))),
+		}
+	}
+
+	pub fn comment(&self, label: &str, id: &str, key_type: &str, backend: &str) -> String {
+		self.comment_template
+			.replace("{label}", label)
+			.replace("{id}", id)
+			.replace("{type}", key_type)
+			.replace("{backend}", backend)
+	}
+
+	// seconds before expiry a certificate gets flagged, check() catches bad values
+	pub fn certificate_warning(&self) -> u64 {
+		cert::parse_duration(&self.list.certificate_warning).unwrap_or(0)
+	}
+}
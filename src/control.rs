@@ -0,0 +1,282 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use daemon;
+use handler::{Counters, Handler};
+use peer::Peer;
+
+// bumped whenever a request or a reply changes in a way old clients can't handle
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// requests are tiny, anything longer than this is garbage
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+// The control socket speaks newline delimited JSON, one request and one reply per line:
+//
+//     {"version":1,"command":"status"}
+//     {"version":1,"ok":true,"result":{"locked":false,...}}
+//
+//     {"version":1,"command":"lock","passphrase":"hunter2"}
+//     {"version":1,"ok":false,"error":"Agent is already locked"}
+//
+// Only the user running the agent may connect.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Command {
+	Status,
+	Identities,
+	Reload,
+	// looks for keys added or removed by another process, without reading the config
+	RefreshKeys,
+	Lock {
+		#[serde(default)]
+		passphrase: Option<String>,
+	},
+	Unlock {
+		#[serde(default)]
+		passphrase: Option<String>,
+	},
+	// forgets the keys the backend holds in memory, files are loaded again on reload
+	DropKeys {
+		backend: String,
+	},
+	Counters,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+	version: u32,
+	#[serde(flatten)]
+	command: Command,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Reply {
+	version: u32,
+	ok: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	result: Option<Value>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+	pub version: String,
+	pub protocol: u32,
+	pub pid: u32,
+	pub uptime: u64,
+	pub agent_socket: String,
+	pub locked: bool,
+	pub backends: Vec<String>,
+	pub keys: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityInfo {
+	pub label: String,
+	pub id: String,
+	// a daemon older than the client doesn't send it
+	#[serde(default)]
+	pub fingerprint: String,
+	pub backend: String,
+	pub source: String,
+	pub comment: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dropped {
+	pub backend: String,
+	pub keys: usize,
+}
+
+// the control socket sits next to the agent socket, so every agent gets its own
+pub fn control_path(agent_socket: &Path) -> PathBuf {
+	let mut path = agent_socket.as_os_str().to_owned();
+	path.push(".ctl");
+	PathBuf::from(path)
+}
+
+pub struct ControlServer {
+	handler: Arc<Mutex<Handler>>,
+	agent_socket: PathBuf,
+	started: Instant,
+}
+
+impl ControlServer {
+
+	// binds the socket readable by the owner only, and serves it on its own thread
+	pub fn spawn(handler: Arc<Mutex<Handler>>, agent_socket: PathBuf, path: &Path) -> Result<(), String> {
+		let listener = daemon::bind_private(path)?;
+
+		let server = Arc::new(ControlServer {
+			handler: handler,
+			agent_socket: agent_socket,
+			started: Instant::now(),
+		});
+		thread::spawn(move || {
+			for stream in listener.incoming().flatten() {
+				let server = server.clone();
+				thread::spawn(move || {
+					let _ = server.handle_client(stream);
+				});
+			}
+		});
+		Ok(())
+	}
+
+	fn handle_client(&self, stream: UnixStream) -> io::Result<()> {
+		let peer = Peer::from_stream(&stream);
+		if !daemon::same_user(peer.uid) {
+			return Ok(());
+		}
+
+		let mut writer = stream.try_clone()?;
+		let mut reader = BufReader::new(stream);
+		loop {
+			let mut line = String::new();
+			let read = reader.by_ref().take(MAX_REQUEST_LEN).read_line(&mut line)?;
+			if read == 0 {
+				return Ok(());
+			}
+			if !line.ends_with('\n') && read as u64 == MAX_REQUEST_LEN {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "request too long"));
+			}
+
+			let reply = match serde_json::from_str::<Request>(&line) {
+				Ok(ref request) if request.version != PROTOCOL_VERSION => {
+					Err(format!("Unsupported protocol version {}, expected {}", request.version, PROTOCOL_VERSION))
+				}
+				Ok(request) => self.execute(request.command, &peer),
+				Err(err) => Err(format!("Invalid request: {}", err)),
+			};
+			let reply = match reply {
+				Ok(result) => Reply { version: PROTOCOL_VERSION, ok: true, result: Some(result), error: None },
+				Err(err) => Reply { version: PROTOCOL_VERSION, ok: false, result: None, error: Some(err) },
+			};
+			let mut encoded = serde_json::to_vec(&reply)
+				.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+			encoded.push(b'\n');
+			writer.write_all(&encoded)?;
+			writer.flush()?;
+		}
+	}
+
+	fn execute(&self, command: Command, peer: &Peer) -> Result<Value, String> {
+		let mut handler = self.handler.lock().unwrap_or_else(|err| err.into_inner());
+		let requested_by = format!("control:{}", peer.name());
+		let result = match command {
+			Command::Status => serde_json::to_value(Status {
+				version: String::from(env!("CARGO_PKG_VERSION")),
+				protocol: PROTOCOL_VERSION,
+				pid: process::id(),
+				uptime: self.started.elapsed().as_secs(),
+				agent_socket: self.agent_socket.display().to_string(),
+				locked: handler.is_locked(),
+				backends: handler.backends().iter().map(|backend| backend.to_string()).collect(),
+				keys: handler.keys().len(),
+			}),
+			Command::Identities => {
+				let identities: Vec<IdentityInfo> = handler.keys().iter()
+					.map(|key| IdentityInfo {
+						label: key.pubkey.label.clone(),
+						id: key.pubkey.id.to_string(),
+						fingerprint: key.pubkey.key.fingerprint(),
+						backend: key.backend.to_string(),
+						source: key.source.describe(),
+						comment: handler.comment(key),
+					})
+					.collect();
+				serde_json::to_value(identities)
+			}
+			Command::Reload => {
+				handler.reload(&requested_by)?;
+				Ok(Value::Null)
+			}
+			Command::RefreshKeys => {
+				handler.refresh_keys();
+				Ok(Value::Null)
+			}
+			Command::Lock { passphrase } => {
+				handler.lock_with(passphrase.unwrap_or_default().as_bytes(), &requested_by)?;
+				Ok(Value::Null)
+			}
+			Command::Unlock { passphrase } => {
+				handler.unlock_with(passphrase.unwrap_or_default().as_bytes(), &requested_by)?;
+				Ok(Value::Null)
+			}
+			Command::DropKeys { backend } => {
+				let keys = handler.drop_keys(&backend, &requested_by)?;
+				serde_json::to_value(Dropped { backend: backend, keys: keys })
+			}
+			Command::Counters => serde_json::to_value(handler.counters()),
+		};
+		result.map_err(|err| format!("Error encoding reply: {}", err))
+	}
+}
+
+// client side of the control socket, what `sekey ctl` uses
+pub struct ControlClient {
+	reader: BufReader<UnixStream>,
+	writer: UnixStream,
+}
+
+impl ControlClient {
+
+	pub fn connect(path: &Path) -> Result<ControlClient, String> {
+		let stream = UnixStream::connect(path)
+			.map_err(|err| format!("Error connecting to {}: {}", path.display(), err))?;
+		let writer = stream.try_clone().map_err(|err| err.to_string())?;
+		Ok(ControlClient { reader: BufReader::new(stream), writer: writer })
+	}
+
+	// sends `command` and hands back the raw result
+	pub fn call(&mut self, command: Command) -> Result<Value, String> {
+		let request = Request { version: PROTOCOL_VERSION, command: command };
+		let mut encoded = serde_json::to_vec(&request).map_err(|err| err.to_string())?;
+		encoded.push(b'\n');
+		self.writer.write_all(&encoded)
+			.and_then(|_| self.writer.flush())
+			.map_err(|err| format!("Error sending request: {}", err))?;
+
+		let mut line = String::new();
+		self.reader.read_line(&mut line).map_err(|err| format!("Error reading reply: {}", err))?;
+		if line.is_empty() {
+			return Err(String::from("Agent closed the connection"));
+		}
+		let reply: Reply = serde_json::from_str(&line).map_err(|err| format!("Invalid reply: {}", err))?;
+		if !reply.ok {
+			return Err(reply.error.unwrap_or_else(|| String::from("Request failed")));
+		}
+		Ok(reply.result.unwrap_or(Value::Null))
+	}
+
+	fn call_typed<T: DeserializeOwned>(&mut self, command: Command) -> Result<T, String> {
+		let result = self.call(command)?;
+		serde_json::from_value(result).map_err(|err| format!("Invalid reply: {}", err))
+	}
+
+	pub fn status(&mut self) -> Result<Status, String> {
+		self.call_typed(Command::Status)
+	}
+
+	pub fn identities(&mut self) -> Result<Vec<IdentityInfo>, String> {
+		self.call_typed(Command::Identities)
+	}
+
+	pub fn counters(&mut self) -> Result<Counters, String> {
+		self.call_typed(Command::Counters)
+	}
+
+	pub fn drop_keys(&mut self, backend: &str) -> Result<Dropped, String> {
+		self.call_typed(Command::DropKeys { backend: backend.to_string() })
+	}
+}
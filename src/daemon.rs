@@ -0,0 +1,190 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::thread;
+
+use libc;
+
+// first descriptor passed by systemd socket activation, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum Fork {
+	Parent(libc::pid_t),
+	Child,
+}
+
+// listening socket inherited from systemd, when LISTEN_PID/LISTEN_FDS were meant for us.
+// The variables are removed so they don't leak to anything we spawn.
+pub fn activated_listener() -> Option<UnixListener> {
+	let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+	let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<i32>().ok());
+	if pid != Some(process::id()) {
+		return None;
+	}
+	env::remove_var("LISTEN_PID");
+	env::remove_var("LISTEN_FDS");
+	env::remove_var("LISTEN_FDNAMES");
+
+	match fds {
+		Some(fds) if fds >= 1 => {
+			unsafe {
+				libc::fcntl(SD_LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+				Some(UnixListener::from_raw_fd(SD_LISTEN_FDS_START))
+			}
+		}
+		_ => None,
+	}
+}
+
+// every socket gets its own pidfile, so agents bound to different sockets don't collide
+pub fn pidfile_path(socket: &Path) -> PathBuf {
+	let mut path = socket.as_os_str().to_owned();
+	path.push(".pid");
+	PathBuf::from(path)
+}
+
+// the pid of the agent owning the pidfile. The agent holds a lock on it for as long as
+// it runs, so a pidfile nobody holds is stale whatever pid it names, which may well have
+// been reused by another process since.
+pub fn read_pidfile(path: &Path) -> Option<libc::pid_t> {
+	let file = File::open(path).ok()?;
+	if lock(&file, libc::LOCK_SH) {
+		return None;
+	}
+	let mut contents = String::new();
+	(&file).read_to_string(&mut contents).ok()?;
+	contents.trim().parse::<libc::pid_t>().ok().filter(|pid| *pid > 0)
+}
+
+// writes our pid and keeps the pidfile locked until we exit
+pub fn write_pidfile(path: &Path) -> Result<(), String> {
+	let error = |err: io::Error| format!("Error writing pidfile {}: {}", path.display(), err);
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		// emptied once we hold the lock, a running agent's pid has to stay
+		.truncate(false)
+		.mode(0o600)
+		.open(path)
+		.map_err(&error)?;
+	if !lock(&file, libc::LOCK_EX) {
+		return Err(format!("Another agent holds the pidfile {}", path.display()));
+	}
+	file.set_len(0).map_err(&error)?;
+	writeln!(file, "{}", process::id()).map_err(&error)?;
+	// never closed, closing would release the lock
+	mem::forget(file);
+	Ok(())
+}
+
+// whether we got the flock without waiting for it
+fn lock(file: &File, operation: libc::c_int) -> bool {
+	unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) == 0 }
+}
+
+// makes sure no other agent is serving `socket`, and removes whatever a dead one left behind
+pub fn check_not_running(socket: &Path, pidfile: &Path) -> Result<(), String> {
+	if let Some(pid) = read_pidfile(pidfile) {
+		return Err(format!("sekey is already running with pid {}", pid));
+	}
+	if fs::symlink_metadata(socket).is_ok() {
+		if UnixStream::connect(socket).is_ok() {
+			return Err(format!("Another agent is already listening on {}", socket.display()));
+		}
+		fs::remove_file(socket)
+			.map_err(|err| format!("Error removing stale socket {}: {}", socket.display(), err))?;
+	}
+	let _ = fs::remove_file(pidfile);
+	Ok(())
+}
+
+// binds a socket only the owner can connect to, replacing one a dead agent left behind
+pub fn bind_private(path: &Path) -> Result<UnixListener, String> {
+	if fs::symlink_metadata(path).is_ok() {
+		if UnixStream::connect(path).is_ok() {
+			return Err(format!("Another agent is already listening on {}", path.display()));
+		}
+		let _ = fs::remove_file(path);
+	}
+	// created private rather than chmod-ed afterwards, which would leave a window where
+	// anybody could connect. umask is process wide, so this is only meant for startup.
+	let umask = unsafe { libc::umask(0o077) };
+	let listener = UnixListener::bind(path);
+	unsafe { libc::umask(umask) };
+	listener.map_err(|err| format!("Error binding to {}: {}", path.display(), err))
+}
+
+// whether the process on the other end runs as us. A peer we can't identify is refused.
+pub fn same_user(peer_uid: Option<libc::uid_t>) -> bool {
+	peer_uid.is_some_and(|uid| uid == unsafe { libc::getuid() })
+}
+
+// detach from the terminal, the parent gets the pid of the daemon back and should exit
+pub fn daemonize() -> Result<Fork, &'static str> {
+	match unsafe { libc::fork() } {
+		-1 => return Err("Error forking the daemon"),
+		0 => {}
+		pid => return Ok(Fork::Parent(pid)),
+	}
+	if unsafe { libc::setsid() } == -1 {
+		return Err("Error creating a new session");
+	}
+	let _ = env::set_current_dir("/");
+
+	let devnull = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.open("/dev/null")
+		.map_err(|_| "Error opening /dev/null")?;
+	for fd in 0..3 {
+		if unsafe { libc::dup2(devnull.as_raw_fd(), fd) } == -1 {
+			return Err("Error redirecting standard streams");
+		}
+	}
+	Ok(Fork::Child)
+}
+
+// SIGTERM, SIGINT and SIGHUP are blocked here and waited for on a dedicated thread.
+// SIGHUP calls `reload`, the others remove `cleanup` and exit. Has to be called before
+// any other thread is spawned so they all inherit the mask.
+pub fn handle_signals<F: Fn() + Send + 'static>(cleanup: Vec<PathBuf>, reload: F) {
+	let set = unsafe {
+		let mut set: libc::sigset_t = mem::zeroed();
+		libc::sigemptyset(&mut set);
+		libc::sigaddset(&mut set, libc::SIGTERM);
+		libc::sigaddset(&mut set, libc::SIGINT);
+		libc::sigaddset(&mut set, libc::SIGHUP);
+		libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+		set
+	};
+
+	thread::spawn(move || loop {
+		let mut signal: libc::c_int = 0;
+		if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+			continue;
+		}
+		if signal == libc::SIGHUP {
+			reload();
+			continue;
+		}
+		for path in &cleanup {
+			let _ = fs::remove_file(path);
+		}
+		process::exit(0);
+	});
+}
+
+pub fn kill(pid: libc::pid_t) -> Result<(), &'static str> {
+	if unsafe { libc::kill(pid, libc::SIGTERM) } == 0 {
+		Ok(())
+	} else {
+		Err("Error killing the agent")
+	}
+}
@@ -1,6 +1,11 @@
-use std::io::{Cursor, Write, Read, BufRead};
-use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
 use eagre_asn1::der::DER;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use openssl::sha::{sha256, sha384, sha512};
+
+use wire::{Reader, Writer};
 
 #[derive(Debug)]
 pub struct ECDSASign {
@@ -14,44 +19,148 @@ der_sequence!{
 		s: NOTAG TYPE Vec<u8>,
 }
 
-pub static CURVE_INDETIFIER: &'static str = "nistp256";
-pub static CURVE_TYPE: &'static str = "ecdsa-sha2-nistp256";
+// the Secure Enclave curve, and the default for new keys
+pub static CURVE_INDETIFIER: &str = "nistp256";
+pub static CURVE_TYPE: &str = "ecdsa-sha2-nistp256";
+
+// NIST curves of RFC 5656, each signing with its own SHA-2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Curve {
+	Nistp256,
+	Nistp384,
+	Nistp521,
+}
+
+pub static CURVES: &[Curve] = &[Curve::Nistp256, Curve::Nistp384, Curve::Nistp521];
+
+// longest r or s of a signature: a P-521 scalar plus the sign byte
+const MAX_SCALAR_LEN: usize = 67;
+// and the longest DER signature, a sequence of two of them
+const MAX_SIGNATURE_LEN: usize = 3 + 2 * (2 + MAX_SCALAR_LEN);
+
+impl Curve {
+
+	pub fn identifier(&self) -> &'static str {
+		match *self {
+			Curve::Nistp256 => CURVE_INDETIFIER,
+			Curve::Nistp384 => "nistp384",
+			Curve::Nistp521 => "nistp521",
+		}
+	}
+
+	pub fn key_type(&self) -> &'static str {
+		match *self {
+			Curve::Nistp256 => CURVE_TYPE,
+			Curve::Nistp384 => "ecdsa-sha2-nistp384",
+			Curve::Nistp521 => "ecdsa-sha2-nistp521",
+		}
+	}
+
+	pub fn nid(&self) -> Nid {
+		match *self {
+			Curve::Nistp256 => Nid::X9_62_PRIME256V1,
+			Curve::Nistp384 => Nid::SECP384R1,
+			Curve::Nistp521 => Nid::SECP521R1,
+		}
+	}
+
+	// uncompressed point: 0x04 followed by both coordinates
+	fn point_len(&self) -> usize {
+		match *self {
+			Curve::Nistp256 => 65,
+			Curve::Nistp384 => 97,
+			Curve::Nistp521 => 133,
+		}
+	}
+
+	pub fn from_identifier(identifier: &[u8]) -> Option<Curve> {
+		CURVES.iter().cloned().find(|curve| curve.identifier().as_bytes() == identifier)
+	}
+
+	pub fn from_key_type(key_type: &[u8]) -> Option<Curve> {
+		CURVES.iter().cloned().find(|curve| curve.key_type().as_bytes() == key_type)
+	}
+
+	pub fn from_nid(nid: Nid) -> Option<Curve> {
+		CURVES.iter().cloned().find(|curve| curve.nid() == nid)
+	}
+
+	// a point only fits one curve, so keys don't need to carry theirs around
+	pub fn from_point(point: &[u8]) -> Option<Curve> {
+		CURVES.iter().cloned().find(|curve| curve.point_len() == point.len())
+	}
+
+	// r and s of a signature are at most this long, unsigned
+	pub fn scalar_len(&self) -> usize {
+		(self.point_len() - 1) / 2
+	}
+
+	pub fn bits(&self) -> usize {
+		match *self {
+			Curve::Nistp256 => 256,
+			Curve::Nistp384 => 384,
+			Curve::Nistp521 => 521,
+		}
+	}
+
+	pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+		match *self {
+			Curve::Nistp256 => sha256(data).to_vec(),
+			Curve::Nistp384 => sha384(data).to_vec(),
+			Curve::Nistp521 => sha512(data).to_vec(),
+		}
+	}
+}
 
-pub struct EcdsaSha2Nistp256;
-impl EcdsaSha2Nistp256 {
+pub struct EcdsaSha2;
+impl EcdsaSha2 {
 
 	// write to SSH-Key Format
-	pub fn write(key: Vec<u8>) -> Vec<u8> {
-		let curvetype = String::from(CURVE_TYPE);
-		let identifier = String::from(CURVE_INDETIFIER);
-		let mut data = vec![];
-		//write curve type
-		data.write_u32::<BigEndian>(curvetype.len() as u32).unwrap();
-		data.write_all(curvetype.as_bytes());
-		//write identifier
-		data.write_u32::<BigEndian>(identifier.len() as u32).unwrap();
-		data.write_all(identifier.as_bytes());
-		//write key
-		data.write_u32::<BigEndian>(key.len() as u32).unwrap();
-		data.write_all(key.as_slice());
-		data 
-	}
-
-	//read from SSH-key Format
-	pub fn read(data: Vec<u8>) -> Vec<u8> {
-		let mut cursor = Cursor::new(data);
-		let len = cursor.read_u32::<BigEndian>().unwrap();
-		//cursor.read(len);
-		cursor.consume(len as usize);
-		let len = cursor.read_u32::<BigEndian>().unwrap();
-		cursor.consume(len as usize);
-		let len = cursor.read_u32::<BigEndian>().unwrap();
-		let mut buffer = vec![0; len as usize];
-		cursor.read(&mut buffer);
-		buffer
-	}
-
-	pub fn parse_asn1(signed_data: Vec<u8>) -> ECDSASign{
-		ECDSASign::der_from_bytes(signed_data).unwrap()
-	}
-}
\ No newline at end of file
+	pub fn write(curve: Curve, key: &[u8]) -> Vec<u8> {
+		let mut writer = Writer::new();
+		writer.write_string(curve.key_type().as_bytes());
+		writer.write_string(curve.identifier().as_bytes());
+		writer.write_string(key);
+		writer.into_vec()
+	}
+
+	// read from SSH-key Format: the curve and public point, once key type, curve
+	// identifier and point agree with each other and nothing follows them
+	pub fn read(data: &[u8]) -> Result<(Curve, Vec<u8>), &'static str> {
+		let mut reader = Reader::new(data);
+		let curve = Curve::from_key_type(reader.read_string()?).ok_or("Not an ECDSA key")?;
+		reader.expect_string(curve.identifier().as_bytes(), "Curve doesn't match key type")?;
+		let point = reader.read_string()?;
+		if point.len() != curve.point_len() || point[0] != 0x04 {
+			return Err("Invalid ECDSA public key");
+		}
+		reader.finish()?;
+		Ok((curve, point.to_vec()))
+	}
+
+	pub fn parse_asn1(signed_data: Vec<u8>) -> Result<ECDSASign, &'static str> {
+		if signed_data.len() > MAX_SIGNATURE_LEN {
+			return Err("Invalid ECDSA signature");
+		}
+		let signature = ECDSASign::der_from_bytes(signed_data).map_err(|_| "Invalid ECDSA signature")?;
+		// r and s are DER integers, which SSH mpints share their encoding with
+		if signature.r.is_empty() || signature.s.is_empty() || signature.r.len() > MAX_SCALAR_LEN || signature.s.len() > MAX_SCALAR_LEN {
+			return Err("Invalid ECDSA signature");
+		}
+		Ok(signature)
+	}
+
+	// checks r and s against the public point and the hash of `data` on the curve
+	pub fn verify(curve: Curve, point: &[u8], data: &[u8], r: &[u8], s: &[u8]) -> Result<bool, &'static str> {
+		let invalid = |_| "Invalid ECDSA public key";
+		let group = EcGroup::from_curve_name(curve.nid()).map_err(|_| "Error creating curve")?;
+		let mut ctx = BigNumContext::new().map_err(|_| "Error creating context")?;
+		let point = EcPoint::from_bytes(&group, point, &mut ctx).map_err(invalid)?;
+		let key = EcKey::from_public_key(&group, &point).map_err(invalid)?;
+
+		let r = BigNum::from_slice(r).map_err(|_| "Invalid ECDSA signature")?;
+		let s = BigNum::from_slice(s).map_err(|_| "Invalid ECDSA signature")?;
+		let signature = EcdsaSig::from_private_components(r, s).map_err(|_| "Invalid ECDSA signature")?;
+		signature.verify(&curve.digest(data), &key).map_err(|_| "Error verifying signature")
+	}
+}
@@ -0,0 +1,31 @@
+use wire::{Reader, Writer};
+
+pub static ED25519_TYPE: &str = "ssh-ed25519";
+
+// public keys are 32 bytes, signatures 64, both travel as they are
+pub const ED25519_KEY_LEN: usize = 32;
+pub const ED25519_SIGNATURE_LEN: usize = 64;
+
+pub struct Ed25519;
+impl Ed25519 {
+
+	// write to SSH-Key Format: key type and the public key
+	pub fn write(key: &[u8]) -> Vec<u8> {
+		let mut writer = Writer::new();
+		writer.write_string(ED25519_TYPE.as_bytes());
+		writer.write_string(key);
+		writer.into_vec()
+	}
+
+	// read from SSH-Key Format
+	pub fn read(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+		let mut reader = Reader::new(data);
+		reader.expect_string(ED25519_TYPE.as_bytes(), "Not an Ed25519 key")?;
+		let key = reader.read_string()?;
+		if key.len() != ED25519_KEY_LEN {
+			return Err("Invalid Ed25519 public key");
+		}
+		reader.finish()?;
+		Ok(key.to_vec())
+	}
+}
@@ -0,0 +1,164 @@
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use daemon;
+use peer::Peer;
+
+pub const EVENTS_VERSION: u32 = 1;
+
+// events a slow subscriber can fall behind before it starts missing them
+const SUBSCRIBER_QUEUE: usize = 256;
+// a subscriber that stops reading altogether is dropped after this long
+const SUBSCRIBER_WRITE_TIMEOUT_SECS: u64 = 5;
+
+// process on the other end of an agent connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+	pub name: String,
+	pub pid: Option<i32>,
+	pub uid: Option<u32>,
+}
+
+impl<'a> From<&'a Peer> for PeerInfo {
+	fn from(peer: &'a Peer) -> Self {
+		PeerInfo {
+			name: peer.name(),
+			pid: peer.pid,
+			uid: peer.uid,
+		}
+	}
+}
+
+// Everything published on the events socket, one JSON object per line:
+//
+//     {"version":1,"time":1514764800,"event":"sign-requested","peer":{...},"key":"d179eb4c..."}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+	ConnectionOpened {
+		peer: PeerInfo,
+	},
+	ConnectionClosed {
+		peer: PeerInfo,
+	},
+	IdentitiesListed {
+		peer: PeerInfo,
+		count: usize,
+	},
+	SignRequested {
+		peer: PeerInfo,
+		key: String,
+		label: Option<String>,
+	},
+	// `reason` says why a request was denied: "locked" or "rate-limited:<scope>"
+	PolicyDecision {
+		peer: PeerInfo,
+		key: String,
+		allowed: bool,
+		reason: Option<String>,
+	},
+	SignCompleted {
+		peer: PeerInfo,
+		key: String,
+	},
+	SignFailed {
+		peer: PeerInfo,
+		key: String,
+		error: String,
+	},
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+	version: u32,
+	time: u64,
+	#[serde(flatten)]
+	event: Event,
+}
+
+// the events socket sits next to the agent socket, like the control socket
+pub fn events_path(agent_socket: &Path) -> PathBuf {
+	let mut path = agent_socket.as_os_str().to_owned();
+	path.push(".events");
+	PathBuf::from(path)
+}
+
+// fans events out to subscribers. Publishing never blocks: every subscriber has its
+// own queue and writer thread, and events for a subscriber that can't keep up are
+// dropped, so no subscriber can slow down or influence the agent.
+#[derive(Clone, Default)]
+pub struct EventBus {
+	subscribers: Arc<Mutex<Vec<SyncSender<String>>>>,
+}
+
+impl EventBus {
+
+	pub fn new() -> Self {
+		EventBus::default()
+	}
+
+	pub fn publish(&self, event: Event) {
+		let mut subscribers = self.subscribers.lock().unwrap_or_else(|err| err.into_inner());
+		if subscribers.is_empty() {
+			return;
+		}
+		let time = SystemTime::now().duration_since(UNIX_EPOCH)
+			.map(|time| time.as_secs())
+			.unwrap_or(0);
+		let record = Record { version: EVENTS_VERSION, time: time, event: event };
+		let mut line = match serde_json::to_string(&record) {
+			Ok(line) => line,
+			Err(_) => return,
+		};
+		line.push('\n');
+
+		subscribers.retain(|subscriber| match subscriber.try_send(line.clone()) {
+			Ok(_) | Err(TrySendError::Full(_)) => true,
+			Err(TrySendError::Disconnected(_)) => false,
+		});
+	}
+
+	// subscribers only listen, whatever they send is never read
+	pub fn subscribe(&self, mut stream: UnixStream) {
+		let _ = stream.shutdown(Shutdown::Read);
+		if stream.set_write_timeout(Some(Duration::from_secs(SUBSCRIBER_WRITE_TIMEOUT_SECS))).is_err() {
+			return;
+		}
+		let (sender, receiver) = mpsc::sync_channel::<String>(SUBSCRIBER_QUEUE);
+		self.subscribers.lock().unwrap_or_else(|err| err.into_inner()).push(sender);
+
+		thread::spawn(move || {
+			for line in receiver {
+				if stream.write_all(line.as_bytes()).is_err() {
+					// timed out or gone, dropping the receiver unsubscribes us on the next publish
+					let _ = stream.shutdown(Shutdown::Both);
+					return;
+				}
+			}
+		});
+	}
+}
+
+pub struct EventServer;
+impl EventServer {
+
+	pub fn spawn(bus: EventBus, path: &Path) -> Result<(), String> {
+		let listener = daemon::bind_private(path)?;
+		thread::spawn(move || {
+			for stream in listener.incoming().flatten() {
+				if daemon::same_user(Peer::from_stream(&stream).uid) {
+					bus.subscribe(stream);
+				}
+			}
+		});
+		Ok(())
+	}
+}
@@ -0,0 +1,156 @@
+use base64;
+use hex;
+use serde_json;
+
+use key::PublicKey;
+use store::PubKey;
+
+pub static EXPORT_FORMATS: &[&str] = &["openssh", "rfc4716", "pem", "jwk", "sec1"];
+
+// RFC 4716 lines are at most 72 bytes, ssh-keygen -e wraps the key at 70
+const RFC4716_LINE_LEN: usize = 72;
+const RFC4716_BODY_LEN: usize = 70;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+	// an authorized_keys line, the label as its comment
+	OpenSsh,
+	// SSH2 PUBLIC KEY block, what ssh-keygen -e writes and some commercial servers want
+	Rfc4716,
+	// PEM SubjectPublicKeyInfo, for openssl and most TLS tooling
+	Pem,
+	// JSON Web Key, kid being the SHA256 fingerprint
+	Jwk,
+	// the uncompressed point in hex, ECDSA keys only
+	Sec1,
+}
+
+impl Format {
+
+	pub fn from_name(name: &str) -> Option<Format> {
+		match name {
+			"openssh" => Some(Format::OpenSsh),
+			"rfc4716" => Some(Format::Rfc4716),
+			"pem" => Some(Format::Pem),
+			"jwk" => Some(Format::Jwk),
+			"sec1" => Some(Format::Sec1),
+			_ => None,
+		}
+	}
+
+	// whether keys of this type can be written in this format at all
+	pub fn supports(&self, key: &PublicKey) -> bool {
+		*self != Format::Sec1 || key.sec1_point().is_some()
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Jwk {
+	pub kty: &'static str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub crv: Option<&'static str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub x: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub y: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub n: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub e: Option<String>,
+	pub kid: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JwkSet {
+	pub keys: Vec<Jwk>,
+}
+
+impl Jwk {
+
+	pub fn new(key: &PublicKey) -> Result<Jwk, &'static str> {
+		let encode = |bytes: &[u8]| Some(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD));
+		let mut jwk = Jwk { kty: "", crv: None, x: None, y: None, n: None, e: None, kid: key.fingerprint() };
+		match *key {
+			PublicKey::Ecdsa(curve, ref point) => {
+				// x and y follow the 0x04 tag, each padded to the size of the curve
+				let len = curve.scalar_len();
+				if point.len() != 1 + 2 * len || point[0] != 0x04 {
+					return Err("Invalid ECDSA public key");
+				}
+				jwk.kty = "EC";
+				jwk.crv = Some(match curve.bits() {
+					256 => "P-256",
+					384 => "P-384",
+					_ => "P-521",
+				});
+				jwk.x = encode(&point[1..1 + len]);
+				jwk.y = encode(&point[1 + len..]);
+			}
+			PublicKey::Ed25519(ref public) => {
+				jwk.kty = "OKP";
+				jwk.crv = Some("Ed25519");
+				jwk.x = encode(public);
+			}
+			PublicKey::Rsa { ref e, ref n } => {
+				jwk.kty = "RSA";
+				jwk.n = encode(n);
+				jwk.e = encode(e);
+			}
+		}
+		Ok(jwk)
+	}
+}
+
+pub fn export(key: &PubKey, format: Format) -> Result<String, &'static str> {
+	match format {
+		Format::OpenSsh => Ok(openssh(key)),
+		Format::Rfc4716 => Ok(rfc4716(key)),
+		Format::Pem => {
+			let pem = key.key.to_pkey()?.public_key_to_pem().map_err(|_| "Error encoding public key")?;
+			String::from_utf8(pem).map(|pem| pem.trim_end().to_string()).map_err(|_| "Error encoding public key")
+		}
+		Format::Jwk => serde_json::to_string_pretty(&Jwk::new(&key.key)?).map_err(|_| "Error encoding public key"),
+		Format::Sec1 => key.key.sec1_point().map(hex::encode).ok_or("Only ECDSA keys have a SEC1 encoding"),
+	}
+}
+
+// every key one after the other, like ssh-add -L, except JWKs which make a single JWK Set
+pub fn export_all(keys: &[PubKey], format: Format) -> Result<String, &'static str> {
+	if format == Format::Jwk {
+		let set = JwkSet { keys: keys.iter().map(|key| Jwk::new(&key.key)).collect::<Result<_, _>>()? };
+		return serde_json::to_string_pretty(&set).map_err(|_| "Error encoding public key");
+	}
+	let exported: Result<Vec<String>, &'static str> = keys.iter().map(|key| export(key, format)).collect();
+	Ok(exported?.join("\n"))
+}
+
+fn openssh(key: &PubKey) -> String {
+	let line = format!("{} {} {}", key.key.key_type(), base64::encode(&key.key.to_blob()), key.label);
+	line.trim_end().to_string()
+}
+
+fn rfc4716(key: &PubKey) -> String {
+	let mut lines = vec!["---- BEGIN SSH2 PUBLIC KEY ----".to_string()];
+	if !key.label.is_empty() {
+		lines.push(rfc4716_header("Comment", &format!("\"{}\"", key.label)));
+	}
+	let encoded = base64::encode(&key.key.to_blob());
+	lines.extend(encoded.as_bytes().chunks(RFC4716_BODY_LEN).map(|chunk| String::from_utf8_lossy(chunk).into_owned()));
+	lines.push("---- END SSH2 PUBLIC KEY ----".to_string());
+	lines.join("\n")
+}
+
+// a header split over as many lines as it takes, each but the last ending in a
+// backslash, without cutting a character in two
+fn rfc4716_header(name: &str, value: &str) -> String {
+	let mut lines = vec![String::new()];
+	for character in format!("{}: {}", name, value).chars() {
+		if lines.last().map_or(0, |line| line.len()) + character.len_utf8() > RFC4716_LINE_LEN - 1 {
+			lines.push(String::new());
+		}
+		if let Some(line) = lines.last_mut() {
+			line.push(character);
+		}
+	}
+	lines.join("\\\n")
+}
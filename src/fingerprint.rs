@@ -0,0 +1,68 @@
+use base64;
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use openssl::sha;
+
+// randomart field, the size ssh-keygen draws
+const FIELD_WIDTH: usize = 17;
+const FIELD_HEIGHT: usize = 9;
+// how often the walk crossed a cell, then S where it started and E where it ended
+static SYMBOLS: &[u8] = b" .o+=*BOX@%&#/^SE";
+
+// SHA256:<base64 without padding>, what ssh-keygen -l prints by default
+pub fn sha256(blob: &[u8]) -> String {
+	let encoded = base64::encode(&sha::sha256(blob));
+	format!("SHA256:{}", encoded.trim_end_matches('='))
+}
+
+// MD5:<hex pairs>, what OpenSSH printed before 6.8 and older servers still log
+pub fn md5(blob: &[u8]) -> String {
+	let mut hasher = Md5::new();
+	let mut digest = [0; 16];
+	hasher.input(blob);
+	hasher.result(&mut digest);
+	let pairs: Vec<String> = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+	format!("MD5:{}", pairs.join(":"))
+}
+
+// the picture ssh-keygen -lv draws: a walk from the middle of the field, steered by
+// two bits of `digest` at a time, framed by `title` above and `hash_name` below
+pub fn randomart(digest: &[u8], title: &str, hash_name: &str) -> String {
+	let most = SYMBOLS.len() - 1;
+	let mut field = [[0usize; FIELD_HEIGHT]; FIELD_WIDTH];
+	let (mut x, mut y) = (FIELD_WIDTH / 2, FIELD_HEIGHT / 2);
+	for &byte in digest {
+		let mut input = byte;
+		for _ in 0..4 {
+			x = if input & 0x1 != 0 { (x + 1).min(FIELD_WIDTH - 1) } else { x.saturating_sub(1) };
+			y = if input & 0x2 != 0 { (y + 1).min(FIELD_HEIGHT - 1) } else { y.saturating_sub(1) };
+			if field[x][y] < most - 2 {
+				field[x][y] += 1;
+			}
+			input >>= 2;
+		}
+	}
+	field[FIELD_WIDTH / 2][FIELD_HEIGHT / 2] = most - 1;
+	field[x][y] = most;
+
+	let mut art = border(&format!("[{}]", title));
+	for row in 0..FIELD_HEIGHT {
+		art.push('|');
+		for column in 0..FIELD_WIDTH {
+			art.push(SYMBOLS[field[column][row].min(most)] as char);
+		}
+		art.push_str("|\n");
+	}
+	art.push_str(&border(&format!("[{}]", hash_name)));
+	art.pop();
+	art
+}
+
+// a line of dashes with `label` in the middle, where ssh-keygen leaves the extra dash
+// on the right
+fn border(label: &str) -> String {
+	let label = if label.len() > FIELD_WIDTH { "" } else { label };
+	let left = (FIELD_WIDTH - label.len()) / 2;
+	let right = FIELD_WIDTH - label.len() - left;
+	format!("+{}{}{}+\n", "-".repeat(left), label, "-".repeat(right))
+}
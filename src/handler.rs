@@ -1,72 +1,395 @@
-use ssh_agent::Response;
-use ssh_agent::Identity;
-use ssh_agent::SSHAgentHandler;
+use agent::{Response, Identity, SSHAgentHandler, HandleResult};
 
-use std::io::{Write};
-use byteorder::{BigEndian, WriteBytesExt};
+use std::mem;
 
-use crypto::digest::Digest;
-use crypto::sha1::Sha1;
+use openssl::memcmp;
+use openssl::sha::sha256;
 
-use Keychain;
-use ecdsa::{EcdsaSha2Nistp256, CURVE_TYPE};
+use audit::Audit;
+use cert;
+use config::Config;
+use ecdsa::CURVE_TYPE;
+use events::{Event, EventBus, PeerInfo};
+use peer::Peer;
+use ratelimit::RateLimiter;
+use rsa::RSA_TYPE;
+use key::{KeyId, PublicKey, Signature};
+use store::{KeyStore, StoredKey};
 
-use ssh_agent::error::HandleResult;
+// everything the daemon reads from disk, built again on every reload
+pub struct Settings {
+	pub config: Config,
+	pub limiter: RateLimiter,
+	pub audit: Audit,
+}
 
+pub type Loader = Box<dyn Fn() -> Result<Settings, String> + Send>;
+
+// what the agent did since it started
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Counters {
+	pub identity_requests: u64,
+	pub sign_requests: u64,
+	pub signatures: u64,
+	pub sign_errors: u64,
+	pub rate_limited: u64,
+	pub refused_locked: u64,
+	pub identities_added: u64,
+	pub identities_removed: u64,
+	pub reloads: u64,
+}
+
+pub struct Handler {
+	store: KeyStore,
+	config: Config,
+	limiter: RateLimiter,
+	audit: Audit,
+	loader: Option<Loader>,
+	// SHA-256 of the passphrase while the agent is locked
+	lock: Option<Vec<u8>>,
+	counters: Counters,
+	events: EventBus,
+}
+
+impl Handler {
+	pub fn new(store: KeyStore, config: Config, limiter: RateLimiter, audit: Audit) -> Self {
+		Self {
+			store: store,
+			config: config,
+			limiter: limiter,
+			audit: audit,
+			loader: None,
+			lock: None,
+			counters: Counters::default(),
+			events: EventBus::new(),
+		}
+	}
+
+	pub fn set_loader(&mut self, loader: Loader) {
+		self.loader = Some(loader);
+	}
+
+	pub fn set_events(&mut self, events: EventBus) {
+		self.events = events;
+	}
+
+	fn publish_decision(&self, peer: &Peer, key_id: &str, denied: Option<String>) {
+		self.events.publish(Event::PolicyDecision {
+			peer: PeerInfo::from(peer),
+			key: key_id.to_string(),
+			allowed: denied.is_none(),
+			reason: denied,
+		});
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	pub fn keys(&mut self) -> Vec<StoredKey> {
+		self.store.public_keys()
+	}
+
+	pub fn backends(&self) -> Vec<&'static str> {
+		self.store.backend_names()
+	}
+
+	pub fn counters(&self) -> Counters {
+		self.counters.clone()
+	}
+
+	pub fn comment(&self, key: &StoredKey) -> String {
+		self.config.comment(&key.pubkey.label, &key.pubkey.id.to_string(), key.key_type(), key.backend)
+	}
+
+	// with verify_signatures on, a signature only goes out once it checks out against the
+	// key, so a faulty backend shows up here instead of as a refusal from the server
+	fn check_signature(&self, peer: &Peer, key_id: &str, public: &PublicKey, data: &[u8], signature: Signature) -> Result<Signature, &'static str> {
+		if !self.config.verify_signatures {
+			return Ok(signature);
+		}
+		let error = match public.verify(data, &signature) {
+			Ok(true) => return Ok(signature),
+			Ok(false) => "Signature doesn't verify against the key",
+			Err(err) => err,
+		};
+		self.audit.record("sign-verify-failed", &[
+			("key", key_id),
+			("peer", peer.name().as_str()),
+			("error", error),
+		]);
+		Err(error)
+	}
+
+	pub fn is_locked(&self) -> bool {
+		self.lock.is_some()
+	}
+
+	// nothing changes unless the whole configuration loads and checks out, a broken
+	// file keeps the old one. Open connections are not touched.
+	pub fn reload(&mut self, requested_by: &str) -> Result<(), String> {
+		let result = self.try_reload();
+		match result {
+			Ok(_) => {
+				self.counters.reloads += 1;
+				self.audit.record("reloaded", &[("by", requested_by)]);
+			}
+			Err(ref err) => {
+				self.audit.record("reload-failed", &[("by", requested_by), ("error", err.as_str())]);
+			}
+		}
+		result
+	}
+
+	fn try_reload(&mut self) -> Result<(), String> {
+		let settings = match self.loader {
+			Some(ref loader) => loader()?,
+			None => return Err(String::from("Reloading is not supported")),
+		};
+		let problems = settings.config.check();
+		if !problems.is_empty() {
+			return Err(problems.join("; "));
+		}
+
+		// backends are only built again when their configuration changed, that would
+		// lose the keys added with ssh-add
+		let rebuild = settings.config.backends != self.config.backends
+			|| settings.config.keychain != self.config.keychain
+			|| settings.config.prompt != self.config.prompt;
+		if rebuild {
+			self.store = KeyStore::from_config(&settings.config)?;
+		} else {
+			self.store.refresh();
+		}
+		self.config = settings.config;
+		let previous = mem::replace(&mut self.limiter, settings.limiter);
+		self.limiter.carry_over(previous);
+		self.audit = settings.audit;
+		Ok(())
+	}
+
+	pub fn refresh_keys(&mut self) {
+		self.store.refresh();
+	}
+
+	pub fn lock_with(&mut self, passphrase: &[u8], requested_by: &str) -> Result<(), &'static str> {
+		if self.lock.is_some() {
+			return Err("Agent is already locked");
+		}
+		self.lock = Some(sha256(passphrase).to_vec());
+		self.audit.record("locked", &[("by", requested_by)]);
+		Ok(())
+	}
+
+	pub fn unlock_with(&mut self, passphrase: &[u8], requested_by: &str) -> Result<(), &'static str> {
+		let unlocked = match self.lock {
+			Some(ref hash) => memcmp::eq(hash, &sha256(passphrase)),
+			None => return Err("Agent is not locked"),
+		};
+		if !unlocked {
+			self.audit.record("unlock-failed", &[("by", requested_by)]);
+			return Err("Wrong passphrase");
+		}
+		self.lock = None;
+		self.audit.record("unlocked", &[("by", requested_by)]);
+		Ok(())
+	}
+
+	pub fn drop_keys(&mut self, backend: &str, requested_by: &str) -> Result<usize, &'static str> {
+		let count = self.store.drop_keys(backend)?;
+		self.audit.record("keys-dropped", &[("backend", backend), ("by", requested_by)]);
+		Ok(count)
+	}
+}
 
-pub struct Handler;
 impl SSHAgentHandler for Handler {
-	fn new() -> Self {
-		Self {}
+
+	fn connected(&mut self, peer: &Peer) {
+		self.events.publish(Event::ConnectionOpened { peer: PeerInfo::from(peer) });
+	}
+
+	fn disconnected(&mut self, peer: &Peer) {
+		self.events.publish(Event::ConnectionClosed { peer: PeerInfo::from(peer) });
 	}
 
-	fn identities(&mut self) -> HandleResult<Response> {
+	fn identities(&mut self, peer: &Peer) -> HandleResult<Response> {
+		self.counters.identity_requests += 1;
+		// a locked agent has no identities, like ssh-agent
+		if self.is_locked() {
+			self.events.publish(Event::IdentitiesListed { peer: PeerInfo::from(peer), count: 0 });
+			return Ok(Response::Identities(vec![]));
+		}
+
 		// list identities and return
-		let keys = Keychain::get_public_keys();
+		let keys = self.store.public_keys();
 		let mut idents = Vec::new();
 		for key in keys {
+			let comment = self.comment(&key);
 			idents.push(Identity{
-				key_blob: EcdsaSha2Nistp256::write(key.key),
-				key_comment: String::from(CURVE_TYPE)
+				key_blob: key.blob(),
+				key_comment: comment
 			});
 		}
+		// every certificate is one more identity, expired ones are left out
+		for (cert, key) in self.store.certificates(cert::now()) {
+			let comment = if cert.comment.is_empty() { self.comment(&key) } else { cert.comment };
+			idents.push(Identity{
+				key_blob: cert.blob,
+				key_comment: comment
+			});
+		}
+		self.events.publish(Event::IdentitiesListed { peer: PeerInfo::from(peer), count: idents.len() });
 		Ok(Response::Identities(idents))
 
 	}
 
-	fn sign_request(&mut self, pubkey: Vec<u8>, data: Vec<u8>, _flags: u32) -> HandleResult<Response> {
-		
-		// parse the pubkey that server send to us, then hash it and we will use that
-		// hash to get the key from the enclave to sign
-		let pubkey = EcdsaSha2Nistp256::read(pubkey);
-		let mut hasher = Sha1::new();
+	fn sign_request(&mut self, peer: &Peer, pubkey: Vec<u8>, data: Vec<u8>, flags: u32) -> HandleResult<Response> {
+		self.counters.sign_requests += 1;
+
+		// the key is parsed and looked up in the identity cache. Unknown keys still get an
+		// ID so the request can be reported and rate limited. Certificates sign with the
+		// key they were issued for.
+		let key = if cert::is_certificate(&pubkey) {
+			self.store.certificate_key(&pubkey, cert::now())
+		} else {
+			PublicKey::from_blob(&pubkey).ok().and_then(|public| self.store.find_by_key(&public))
+		};
+		let id = match key {
+			Some(ref key) => key.pubkey.id.clone(),
+			None => {
+				let pubkey = cert::Certificate::parse(&pubkey).map(|cert| cert.subject_key()).unwrap_or(pubkey);
+				PublicKey::from_blob(&pubkey).map(|public| public.id()).unwrap_or_else(|_| KeyId::of(&pubkey))
+			}
+		};
+		let public = key.as_ref().map(|key| key.pubkey.key.clone());
+		let algorithm = key.as_ref().map(|key| key.pubkey.key.signature_algorithm(flags)).unwrap_or(CURVE_TYPE);
+		let key_id = id.to_string();
+		self.events.publish(Event::SignRequested {
+			peer: PeerInfo::from(peer),
+			key: key_id.clone(),
+			label: key.map(|key| key.pubkey.label),
+		});
 
-		let mut hash: [u8; 20] = [0; 20];
-		hasher.input(pubkey.as_slice());
-		hasher.result(&mut hash);
+		if self.is_locked() {
+			self.counters.refused_locked += 1;
+			self.publish_decision(peer, &key_id, Some(String::from("locked")));
+			return Ok(Response::Failure);
+		}
 
+		// refuse before asking the enclave, so a flood of requests never reaches Touch ID
+		if let Err(scope) = self.limiter.check(&id, &key_id, peer) {
+			self.audit.record("sign-rate-limited", &[
+				("scope", scope.name()),
+				("key", key_id.as_str()),
+				("peer", peer.name().as_str()),
+			]);
+			self.counters.rate_limited += 1;
+			self.publish_decision(peer, &key_id, Some(format!("rate-limited:{}", scope.name())));
+			return Ok(Response::Failure);
+		}
+		// SHA-1 is only there for servers too old to ask for anything else
+		if algorithm == RSA_TYPE && !self.config.allow_rsa_sha1 {
+			self.audit.record("sign-refused-sha1", &[
+				("key", key_id.as_str()),
+				("peer", peer.name().as_str()),
+			]);
+			self.publish_decision(peer, &key_id, Some(String::from("rsa-sha1")));
+			return Ok(Response::Failure);
+		}
+		self.publish_decision(peer, &key_id, None);
 
 		// here we sign the request and do all the enclave communication
-		let signed = Keychain::sign_data(data, hash.to_vec())?;
-		let ecdsasign = EcdsaSha2Nistp256::parse_asn1(signed);
+		let signed = match public {
+			Some(ref public) => self.store.sign(&id, &data, algorithm)
+				.and_then(|signature| self.check_signature(peer, &key_id, public, &data, signature)),
+			None => Err("Key not found"),
+		};
+		let signature = match signed {
+			Ok(signature) => signature,
+			Err(err) => {
+				self.counters.sign_errors += 1;
+				self.events.publish(Event::SignFailed {
+					peer: PeerInfo::from(peer),
+					key: key_id,
+					error: err.to_string(),
+				});
+				return Err(err.into());
+			}
+		};
+		self.counters.signatures += 1;
+		self.events.publish(Event::SignCompleted { peer: PeerInfo::from(peer), key: key_id });
 
-		//sign that we would return
-		let mut signature:Vec<u8> = Vec::new();
+		// response signature
+		Ok(Response::SignResponse(signature))
+	}
 
-		//write signR
-		signature.write_u32::<BigEndian>(ecdsasign.r.len() as u32).unwrap();
-		signature.write_all(ecdsasign.r.as_slice())?;
-		
-		//write signS
-		signature.write_u32::<BigEndian>(ecdsasign.s.len() as u32).unwrap();
-		signature.write_all(ecdsasign.s.as_slice())?;
+	fn add_identity(&mut self, peer: &Peer, key: Vec<u8>) -> HandleResult<Response> {
+		if self.is_locked() {
+			return Ok(Response::Failure);
+		}
+		match self.store.add_identity(&key) {
+			Ok(_) => {
+				self.counters.identities_added += 1;
+				self.audit.record("identity-added", &[("peer", peer.name().as_str())]);
+				Ok(Response::Success)
+			}
+			Err(_) => Ok(Response::Failure),
+		}
+	}
 
-		// response signature
-		Ok(Response::SignResponse {
-			algo_name: String::from(CURVE_TYPE),
-			signature: signature
-		})
+	fn remove_identity(&mut self, peer: &Peer, pubkey: Vec<u8>) -> HandleResult<Response> {
+		if self.is_locked() {
+			return Ok(Response::Failure);
+		}
+		// removing a certificate leaves its key alone
+		if cert::is_certificate(&pubkey) {
+			return match self.store.remove_certificate(&pubkey) {
+				Ok(_) => {
+					self.counters.identities_removed += 1;
+					self.audit.record("certificate-removed", &[("peer", peer.name().as_str())]);
+					Ok(Response::Success)
+				}
+				Err(_) => Ok(Response::Failure),
+			};
+		}
+		let key_id = match PublicKey::from_blob(&pubkey).ok().and_then(|key| self.store.find_by_key(&key)) {
+			Some(key) => key.pubkey.id,
+			None => return Ok(Response::Failure),
+		};
+		match self.store.remove_identity(&key_id) {
+			Ok(_) => {
+				self.counters.identities_removed += 1;
+				self.audit.record("identity-removed", &[
+					("key", key_id.to_string().as_str()),
+					("peer", peer.name().as_str()),
+				]);
+				Ok(Response::Success)
+			}
+			Err(_) => Ok(Response::Failure),
+		}
 	}
 
+	fn remove_all_identities(&mut self, peer: &Peer) -> HandleResult<Response> {
+		if self.is_locked() {
+			return Ok(Response::Failure);
+		}
+		self.store.remove_all_identities();
+		self.audit.record("identities-removed", &[("peer", peer.name().as_str())]);
+		Ok(Response::Success)
+	}
+
+	fn lock(&mut self, peer: &Peer, passphrase: Vec<u8>) -> HandleResult<Response> {
+		match self.lock_with(&passphrase, &peer.name()) {
+			Ok(_) => Ok(Response::Success),
+			Err(_) => Ok(Response::Failure),
+		}
+	}
+
+	fn unlock(&mut self, peer: &Peer, passphrase: Vec<u8>) -> HandleResult<Response> {
+		match self.unlock_with(&passphrase, &peer.name()) {
+			Ok(_) => Ok(Response::Success),
+			Err(_) => Ok(Response::Failure),
+		}
+	}
 
 }
\ No newline at end of file
@@ -0,0 +1,51 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+static SEKEY_HOME_FOLDER: &str = ".sekey";
+pub static SSH_AGENT_PIPE: &str = "ssh-agent.ssh";
+
+// folder holding the agent socket and the rest of sekey files,
+// `SEKEY_HOME` takes precedence over `~/.sekey`
+pub fn sekey_home() -> Result<PathBuf, &'static str> {
+	if let Some(home) = env::var_os("SEKEY_HOME") {
+		if !home.is_empty() {
+			return Ok(absolute(Path::new(&home)));
+		}
+	}
+	env::home_dir()
+		.map(|home| home.join(SEKEY_HOME_FOLDER))
+		.ok_or("Impossible to get home dir!")
+}
+
+pub fn create_sekey_home() -> Result<PathBuf, &'static str> {
+	let home = sekey_home()?;
+	if !home.exists() {
+		fs::create_dir_all(&home).map_err(|_| "Error creating home folder")?;
+	}
+	Ok(home)
+}
+
+pub fn default_socket() -> Result<PathBuf, &'static str> {
+	Ok(sekey_home()?.join(SSH_AGENT_PIPE))
+}
+
+// paths from configuration files, `~/` stands for the home directory
+pub fn expand(path: &str) -> PathBuf {
+	if path == "~" || path.starts_with("~/") {
+		if let Some(home) = env::home_dir() {
+			return if path == "~" { home } else { home.join(&path[2..]) };
+		}
+	}
+	absolute(Path::new(path))
+}
+
+// SSH_AUTH_SOCK is used from other working directories, so relative paths are useless there
+pub fn absolute(path: &Path) -> PathBuf {
+	if path.is_absolute() {
+		return path.to_path_buf();
+	}
+	env::current_dir()
+		.map(|dir| dir.join(path))
+		.unwrap_or_else(|_| path.to_path_buf())
+}
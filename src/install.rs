@@ -0,0 +1,113 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub static LAUNCHD_LABEL: &str = "com.ntrippar.sekey";
+pub static SYSTEMD_SOCKET_UNIT: &str = "sekey.socket";
+pub static SYSTEMD_SERVICE_UNIT: &str = "sekey.service";
+
+pub fn systemd_socket_unit(socket: &Path) -> String {
+	format!("[Unit]
+Description=SeKey SSH agent socket
+
+[Socket]
+ListenStream={}
+SocketMode=0600
+DirectoryMode=0700
+
+[Install]
+WantedBy=sockets.target
+", socket.display())
+}
+
+pub fn systemd_service_unit(exe: &Path, socket: &Path) -> String {
+	format!("[Unit]
+Description=SeKey SSH agent
+Requires={socket_unit}
+After={socket_unit}
+
+[Service]
+ExecStart={exe} --daemon --foreground --socket {socket}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+
+[Install]
+Also={socket_unit}
+", socket_unit = SYSTEMD_SOCKET_UNIT, exe = systemd_quote(&exe.display().to_string()), socket = systemd_quote(&socket.display().to_string()))
+}
+
+pub fn launchd_plist(exe: &Path, socket: &Path) -> String {
+	format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{exe}</string>
+        <string>--daemon</string>
+        <string>--foreground</string>
+        <string>--socket</string>
+        <string>{socket}</string>
+    </array>
+    <key>StandardErrorPath</key>
+    <string>/dev/null</string>
+    <key>StandardOutPath</key>
+    <string>/dev/null</string>
+    <key>KeepAlive</key>
+    <true/>
+</dict>
+</plist>
+"#, label = LAUNCHD_LABEL, exe = xml_escape(&exe.display().to_string()), socket = xml_escape(&socket.display().to_string()))
+}
+
+// ~/.config/systemd/user, honoring XDG_CONFIG_HOME
+pub fn systemd_user_dir() -> Result<PathBuf, &'static str> {
+	let config = match env::var_os("XDG_CONFIG_HOME") {
+		Some(ref config) if !config.is_empty() => PathBuf::from(config),
+		_ => env::home_dir().ok_or("Impossible to get home dir!")?.join(".config"),
+	};
+	Ok(config.join("systemd").join("user"))
+}
+
+pub fn launchd_agents_dir() -> Result<PathBuf, &'static str> {
+	Ok(env::home_dir().ok_or("Impossible to get home dir!")?.join("Library").join("LaunchAgents"))
+}
+
+pub fn install_systemd(exe: &Path, socket: &Path, force: bool) -> Result<Vec<PathBuf>, String> {
+	let dir = systemd_user_dir()?;
+	let socket_unit = dir.join(SYSTEMD_SOCKET_UNIT);
+	let service_unit = dir.join(SYSTEMD_SERVICE_UNIT);
+	write_file(&socket_unit, &systemd_socket_unit(socket), force)?;
+	write_file(&service_unit, &systemd_service_unit(exe, socket), force)?;
+	Ok(vec![socket_unit, service_unit])
+}
+
+pub fn install_launchd(exe: &Path, socket: &Path, force: bool) -> Result<PathBuf, String> {
+	let plist = launchd_agents_dir()?.join(format!("{}.plist", LAUNCHD_LABEL));
+	write_file(&plist, &launchd_plist(exe, socket), force)?;
+	Ok(plist)
+}
+
+fn write_file(path: &Path, contents: &str, force: bool) -> Result<(), String> {
+	if path.exists() && !force {
+		return Err(format!("{} already exists, use --force to overwrite it", path.display()));
+	}
+	if let Some(dir) = path.parent() {
+		fs::create_dir_all(dir).map_err(|err| format!("Error creating {}: {}", dir.display(), err))?;
+	}
+	fs::write(path, contents).map_err(|err| format!("Error writing {}: {}", path.display(), err))
+}
+
+fn systemd_quote(value: &str) -> String {
+	if value.chars().any(|c| c.is_whitespace() || c == '"' || c == '\\') {
+		format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+	} else {
+		value.to_string()
+	}
+}
+
+fn xml_escape(value: &str) -> String {
+	value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
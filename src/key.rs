@@ -0,0 +1,454 @@
+use std::fmt;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use hex;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Public};
+use openssl::rsa::Rsa as RsaKey;
+use openssl::sha::sha256;
+use openssl::sign::Verifier;
+
+use agent::{SSH_AGENT_RSA_SHA2_256, SSH_AGENT_RSA_SHA2_512};
+use cert::key_type_name;
+use ecdsa::{Curve, EcdsaSha2};
+use ed25519::{Ed25519, ED25519_SIGNATURE_LEN, ED25519_TYPE};
+use fingerprint;
+use rsa::{Rsa, RSA_SHA2_256, RSA_SHA2_512, RSA_TYPE};
+use wire::{decode_mpint, Reader, Writer};
+
+// ID of a key: the SHA-1 of its public key material, the way the Keychain computes
+// kSecAttrApplicationLabel for a point, so every backend shares the same IDs
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KeyId(Vec<u8>);
+
+impl KeyId {
+
+	// an ID as a backend stores it
+	pub fn new(id: Vec<u8>) -> KeyId {
+		KeyId(id)
+	}
+
+	pub fn of(material: &[u8]) -> KeyId {
+		let mut hasher = Sha1::new();
+		let mut hash: [u8; 20] = [0; 20];
+		hasher.input(material);
+		hasher.result(&mut hash);
+		KeyId(hash.to_vec())
+	}
+
+	// IDs as the listing prints them
+	pub fn from_hex(id: &str) -> Result<KeyId, &'static str> {
+		match hex::decode(id) {
+			Ok(ref id) if !id.is_empty() => Ok(KeyId(id.clone())),
+			_ => Err("Invalid key ID"),
+		}
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl fmt::Display for KeyId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", hex::encode(&self.0))
+	}
+}
+
+// public key of one of the algorithms keys can live under
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PublicKey {
+	// uncompressed SEC1 point
+	Ecdsa(Curve, Vec<u8>),
+	Ed25519(Vec<u8>),
+	// public exponent and modulus, unsigned big endian without leading zeros
+	Rsa { e: Vec<u8>, n: Vec<u8> },
+}
+
+impl PublicKey {
+
+	pub fn key_type(&self) -> &'static str {
+		match *self {
+			PublicKey::Ecdsa(curve, _) => curve.key_type(),
+			PublicKey::Ed25519(_) => ED25519_TYPE,
+			PublicKey::Rsa { .. } => RSA_TYPE,
+		}
+	}
+
+	// what the ID hashes: the point, the Ed25519 key, or the RSA exponent and modulus
+	// as mpints, which is whatever follows the curve or key type in the blob
+	fn material(&self) -> Vec<u8> {
+		match *self {
+			PublicKey::Ecdsa(_, ref point) => point.clone(),
+			PublicKey::Ed25519(ref key) => key.clone(),
+			PublicKey::Rsa { ref e, ref n } => {
+				let mut writer = Writer::new();
+				writer.write_mpint(e);
+				writer.write_mpint(n);
+				writer.into_vec()
+			}
+		}
+	}
+
+	pub fn id(&self) -> KeyId {
+		KeyId::of(&self.material())
+	}
+
+	// size as ssh-keygen -l reports it
+	pub fn bits(&self) -> usize {
+		match *self {
+			PublicKey::Ecdsa(curve, _) => curve.bits(),
+			PublicKey::Ed25519(_) => 256,
+			PublicKey::Rsa { ref n, .. } => match n.first() {
+				Some(&top) => n.len() * 8 - top.leading_zeros() as usize,
+				None => 0,
+			},
+		}
+	}
+
+	pub fn fingerprint(&self) -> String {
+		fingerprint::sha256(&self.to_blob())
+	}
+
+	pub fn md5_fingerprint(&self) -> String {
+		fingerprint::md5(&self.to_blob())
+	}
+
+	// the randomart of ssh-keygen -lv, drawn from the SHA256 fingerprint
+	pub fn randomart(&self) -> String {
+		let title = format!("{} {}", key_type_name(self.key_type()), self.bits());
+		fingerprint::randomart(&sha256(&self.to_blob()), &title, "SHA256")
+	}
+
+	// the public key as it travels in the agent protocol
+	pub fn to_blob(&self) -> Vec<u8> {
+		match *self {
+			PublicKey::Ecdsa(curve, ref point) => EcdsaSha2::write(curve, point),
+			PublicKey::Ed25519(ref key) => Ed25519::write(key),
+			PublicKey::Rsa { ref e, ref n } => Rsa::write(e, n),
+		}
+	}
+
+	pub fn from_blob(blob: &[u8]) -> Result<PublicKey, &'static str> {
+		let key_type = Reader::new(blob).read_string()?;
+		if key_type == ED25519_TYPE.as_bytes() {
+			Ed25519::read(blob).map(PublicKey::Ed25519)
+		} else if key_type == RSA_TYPE.as_bytes() {
+			Rsa::read(blob).map(|(e, n)| PublicKey::Rsa { e: e, n: n })
+		} else if Curve::from_key_type(key_type).is_some() {
+			EcdsaSha2::read(blob).map(|(curve, point)| PublicKey::Ecdsa(curve, point))
+		} else {
+			Err("Unsupported key type")
+		}
+	}
+
+	pub fn from_sec1(point: &[u8]) -> Result<PublicKey, &'static str> {
+		match Curve::from_point(point) {
+			Some(curve) if point[0] == 0x04 => Ok(PublicKey::Ecdsa(curve, point.to_vec())),
+			_ => Err("Invalid ECDSA public key"),
+		}
+	}
+
+	pub fn sec1_point(&self) -> Option<&[u8]> {
+		match *self {
+			PublicKey::Ecdsa(_, ref point) => Some(point),
+			_ => None,
+		}
+	}
+
+	pub fn from_pkey<T: HasPublic>(key: &PKeyRef<T>) -> Result<PublicKey, &'static str> {
+		match key.id() {
+			Id::ED25519 => Ok(PublicKey::Ed25519(key.raw_public_key().map_err(|_| "Error encoding public key")?)),
+			Id::RSA => {
+				let rsa = key.rsa().map_err(|_| "Invalid key")?;
+				Ok(PublicKey::Rsa { e: rsa.e().to_vec(), n: rsa.n().to_vec() })
+			}
+			Id::EC => {
+				let ec_key = key.ec_key().map_err(|_| "Invalid key")?;
+				let curve = ec_key.group().curve_name().and_then(Curve::from_nid).ok_or("Unsupported curve")?;
+				let group = EcGroup::from_curve_name(curve.nid()).map_err(|_| "Error creating curve")?;
+				let mut ctx = BigNumContext::new().map_err(|_| "Error creating context")?;
+				let point = ec_key.public_key()
+					.to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)
+					.map_err(|_| "Error encoding public key")?;
+				Ok(PublicKey::Ecdsa(curve, point))
+			}
+			_ => Err("Unsupported key type"),
+		}
+	}
+
+	pub fn to_pkey(&self) -> Result<PKey<Public>, &'static str> {
+		let invalid = |_| "Invalid public key";
+		match *self {
+			PublicKey::Ecdsa(curve, ref point) => {
+				let group = EcGroup::from_curve_name(curve.nid()).map_err(|_| "Error creating curve")?;
+				let mut ctx = BigNumContext::new().map_err(invalid)?;
+				let point = EcPoint::from_bytes(&group, point, &mut ctx).map_err(invalid)?;
+				EcKey::from_public_key(&group, &point).and_then(PKey::from_ec_key).map_err(invalid)
+			}
+			PublicKey::Ed25519(ref key) => PKey::public_key_from_raw_bytes(key, Id::ED25519).map_err(invalid),
+			PublicKey::Rsa { ref e, ref n } => {
+				let e = BigNum::from_slice(e).map_err(invalid)?;
+				let n = BigNum::from_slice(n).map_err(invalid)?;
+				RsaKey::from_public_components(n, e).and_then(PKey::from_rsa).map_err(invalid)
+			}
+		}
+	}
+
+	// SubjectPublicKeyInfo, what `openssl pkey -pubin` reads
+	pub fn from_spki_der(der: &[u8]) -> Result<PublicKey, &'static str> {
+		let key = PKey::public_key_from_der(der).map_err(|_| "Invalid public key")?;
+		PublicKey::from_pkey(&key)
+	}
+
+	pub fn to_spki_der(&self) -> Result<Vec<u8>, &'static str> {
+		self.to_pkey()?.public_key_to_der().map_err(|_| "Error encoding public key")
+	}
+
+	// whether `signature` was made by this key over `data`, errors for signatures of
+	// another algorithm
+	pub fn verify(&self, data: &[u8], signature: &Signature) -> Result<bool, &'static str> {
+		let failed = |_| "Error verifying signature";
+		match (self, signature) {
+			(PublicKey::Ecdsa(curve, point), Signature::Ecdsa { curve: signed, r, s }) if curve == signed => {
+				EcdsaSha2::verify(*curve, point, data, r, s)
+			}
+			(PublicKey::Ed25519(_), Signature::Ed25519(bytes)) => {
+				let key = self.to_pkey()?;
+				Verifier::new_without_digest(&key)
+					.and_then(|mut verifier| verifier.verify_oneshot(bytes, data))
+					.map_err(failed)
+			}
+			(PublicKey::Rsa { .. }, Signature::Rsa { algorithm, signature: bytes }) => {
+				let digest = if *algorithm == RSA_SHA2_512 {
+					MessageDigest::sha512()
+				} else if *algorithm == RSA_SHA2_256 {
+					MessageDigest::sha256()
+				} else {
+					MessageDigest::sha1()
+				};
+				let key = self.to_pkey()?;
+				let mut verifier = Verifier::new(digest, &key).map_err(failed)?;
+				verifier.update(data).map_err(failed)?;
+				verifier.verify(bytes).map_err(failed)
+			}
+			_ => Err("Signature algorithm doesn't match the key"),
+		}
+	}
+
+	// algorithm the key signs with: the key type, except for RSA where the sign request
+	// flags pick SHA-2 over SHA-1
+	pub fn signature_algorithm(&self, flags: u32) -> &'static str {
+		match *self {
+			PublicKey::Rsa { .. } if flags & SSH_AGENT_RSA_SHA2_512 != 0 => RSA_SHA2_512,
+			PublicKey::Rsa { .. } if flags & SSH_AGENT_RSA_SHA2_256 != 0 => RSA_SHA2_256,
+			_ => self.key_type(),
+		}
+	}
+}
+
+// signature made by one of the keys above
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signature {
+	// r and s, unsigned big endian without leading zeros
+	Ecdsa { curve: Curve, r: Vec<u8>, s: Vec<u8> },
+	Ed25519(Vec<u8>),
+	// `algorithm` is the hash it was made with, see PublicKey::signature_algorithm
+	Rsa { algorithm: &'static str, signature: Vec<u8> },
+}
+
+impl Signature {
+
+	pub fn algorithm(&self) -> &'static str {
+		match *self {
+			Signature::Ecdsa { curve, .. } => curve.key_type(),
+			Signature::Ed25519(_) => ED25519_TYPE,
+			Signature::Rsa { algorithm, .. } => algorithm,
+		}
+	}
+
+	// r and s have to be positive and fit the curve
+	fn ecdsa(curve: Curve, r: &[u8], s: &[u8]) -> Result<Signature, &'static str> {
+		for scalar in &[r, s] {
+			if scalar.is_empty() || scalar.len() > curve.scalar_len() {
+				return Err("Invalid ECDSA signature");
+			}
+		}
+		Ok(Signature::Ecdsa { curve: curve, r: r.to_vec(), s: s.to_vec() })
+	}
+
+	// the ECDSA-Sig-Value the Keychain and openssl hand back
+	pub fn from_ecdsa_der(curve: Curve, der: Vec<u8>) -> Result<Signature, &'static str> {
+		let signature = EcdsaSha2::parse_asn1(der)?;
+		let r = decode_mpint(&signature.r).map_err(|_| "Invalid ECDSA signature")?;
+		let s = decode_mpint(&signature.s).map_err(|_| "Invalid ECDSA signature")?;
+		Signature::ecdsa(curve, r, s)
+	}
+
+	pub fn to_ecdsa_der(&self) -> Result<Vec<u8>, &'static str> {
+		match *self {
+			Signature::Ecdsa { ref r, ref s, .. } => {
+				let r = BigNum::from_slice(r).map_err(|_| "Invalid ECDSA signature")?;
+				let s = BigNum::from_slice(s).map_err(|_| "Invalid ECDSA signature")?;
+				EcdsaSig::from_private_components(r, s)
+					.and_then(|signature| signature.to_der())
+					.map_err(|_| "Invalid ECDSA signature")
+			}
+			_ => Err("Not an ECDSA signature"),
+		}
+	}
+
+	// SSH signature blob: the algorithm followed by the signature, r and s for ECDSA
+	pub fn to_blob(&self) -> Vec<u8> {
+		let mut signature = Writer::new();
+		match *self {
+			Signature::Ecdsa { ref r, ref s, .. } => {
+				signature.write_mpint(r);
+				signature.write_mpint(s);
+			}
+			Signature::Ed25519(ref bytes) | Signature::Rsa { signature: ref bytes, .. } => signature.write_raw(bytes),
+		}
+		let mut writer = Writer::new();
+		writer.write_string(self.algorithm().as_bytes());
+		writer.write_string(&signature.into_vec());
+		writer.into_vec()
+	}
+
+	pub fn from_blob(blob: &[u8]) -> Result<Signature, &'static str> {
+		let mut reader = Reader::new(blob);
+		let algorithm = reader.read_string()?;
+		let bytes = reader.read_string()?;
+		reader.finish()?;
+
+		if algorithm == ED25519_TYPE.as_bytes() {
+			if bytes.len() != ED25519_SIGNATURE_LEN {
+				return Err("Invalid Ed25519 signature");
+			}
+			return Ok(Signature::Ed25519(bytes.to_vec()));
+		}
+		if let Some(&algorithm) = [RSA_TYPE, RSA_SHA2_256, RSA_SHA2_512].iter().find(|name| name.as_bytes() == algorithm) {
+			return Ok(Signature::Rsa { algorithm: algorithm, signature: bytes.to_vec() });
+		}
+		let curve = Curve::from_key_type(algorithm).ok_or("Unsupported signature algorithm")?;
+		let mut reader = Reader::new(bytes);
+		let r = reader.read_mpint()?;
+		let s = reader.read_mpint()?;
+		reader.finish()?;
+		Signature::ecdsa(curve, r, s)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// a signature as the Keychain or openssl hand it over and as it goes out to clients.
+	// The SSHSIG files made from them pass ssh-keygen -Y check-novalidate.
+	struct Vector {
+		key: &'static str,
+		der: &'static str,
+		ssh: &'static str,
+	}
+
+	// what ssh-keygen -Y signs for "sekey test vector\n" in the "file" namespace
+	const SIGNED: &str = concat!(
+		"5353485349470000000466696c650000000000000006736861353132000000407b7bba9f3471addb1feb41ceba2cbf51",
+		"e95e8e76f976664a5b21f38af6cab4ebb8f72d8d937e91437b9474295a71a3af1bc4cda07b463b3e72b28178cb6c7c2a",
+	);
+
+	// P-256, r and s with their top bit set so DER pads them with a zero byte
+	const P256_HIGH_BIT: Vector = Vector {
+		key: concat!(
+			"0000001365636473612d736861322d6e69737470323536000000086e697374703235360000004104dfc4451a0a2e8cce",
+			"a09e32e55d76c9169be9c3a4c711162c075f8ac03c31b6d4922a58054d82c01b03f0c0949f8d4397167cde94e4a11a84",
+			"6412a7ce03f26746",
+		),
+		der: concat!(
+			"3046022100bbfb3ae5892868a78acaca5600fae583353c3b13e914ded340726b1915df410b022100e7c0646d5a6afeae",
+			"cee2e23c73d8bf3618e8713edbe60062ccbdba82747e4536",
+		),
+		ssh: concat!(
+			"0000001365636473612d736861322d6e697374703235360000004a0000002100bbfb3ae5892868a78acaca5600fae583",
+			"353c3b13e914ded340726b1915df410b0000002100e7c0646d5a6afeaecee2e23c73d8bf3618e8713edbe60062ccbdba",
+			"82747e4536",
+		),
+	};
+
+	// P-256, r a byte shorter than the curve
+	const P256_SHORT_R: Vector = Vector {
+		key: concat!(
+			"0000001365636473612d736861322d6e69737470323536000000086e697374703235360000004104dfc4451a0a2e8cce",
+			"a09e32e55d76c9169be9c3a4c711162c075f8ac03c31b6d4922a58054d82c01b03f0c0949f8d4397167cde94e4a11a84",
+			"6412a7ce03f26746",
+		),
+		der: concat!(
+			"3044022000f109f8d92ee5603f4038d7d1c5486a001de56f4dca71ad1b37a0f9e96e889d0220148b14caed98761b6e86",
+			"60789002fcfae3124ac26e5ef8dce10432d71e24539a",
+		),
+		ssh: concat!(
+			"0000001365636473612d736861322d6e69737470323536000000480000002000f109f8d92ee5603f4038d7d1c5486a00",
+			"1de56f4dca71ad1b37a0f9e96e889d00000020148b14caed98761b6e8660789002fcfae3124ac26e5ef8dce10432d71e",
+			"24539a",
+		),
+	};
+
+	// P-521, r and s longer than 64 bytes
+	const P521: Vector = Vector {
+		key: concat!(
+			"0000001365636473612d736861322d6e69737470353231000000086e69737470353231000000850400f26f767d56a07a",
+			"c86478ffb6be94e28e2614ca429b57a2e5050d95e2a0eb6b554a6cffd23af90d75c79da243ed33e82ff605b8bbbe07c5",
+			"a6b67672d04b59cc126501922280030b58a7aa7367ef71005ce727d79c2eea075979d831c9eb478dbb722aac3b118d66",
+			"4270e3606c2c5a19409e220343a0c699da1beac492ce16dcf98f2ea0",
+		),
+		der: concat!(
+			"308188024201a4735fa9d0d10b3374b00c0317387ac5da52dc88f0bdc598fb9615793a9355b571ee509d3d39adbe81c4",
+			"789895af38e3fd058237f1926659cc2251fd43c55f8734024201d159c50419b41ac57f7e12dec47249ac9a8e4eecf463",
+			"373674b6d9e7285427f3cfc6b7ea648c4157a5887620d6bd04e4b4b8932b99101af2017e7e6f384bb6fdf3",
+		),
+		ssh: concat!(
+			"0000001365636473612d736861322d6e697374703532310000008c0000004201a4735fa9d0d10b3374b00c0317387ac5",
+			"da52dc88f0bdc598fb9615793a9355b571ee509d3d39adbe81c4789895af38e3fd058237f1926659cc2251fd43c55f87",
+			"340000004201d159c50419b41ac57f7e12dec47249ac9a8e4eecf463373674b6d9e7285427f3cfc6b7ea648c4157a588",
+			"7620d6bd04e4b4b8932b99101af2017e7e6f384bb6fdf3",
+		),
+	};
+
+	fn check(vector: &Vector, curve: Curve) {
+		let key = PublicKey::from_blob(&hex::decode(vector.key).unwrap()).unwrap();
+		let der = hex::decode(vector.der).unwrap();
+		let ssh = hex::decode(vector.ssh).unwrap();
+
+		let signature = Signature::from_ecdsa_der(curve, der.clone()).unwrap();
+		assert_eq!(signature.to_blob(), ssh);
+		assert_eq!(Signature::from_blob(&ssh), Ok(signature.clone()));
+		assert_eq!(signature.to_ecdsa_der(), Ok(der));
+		assert_eq!(key.verify(&hex::decode(SIGNED).unwrap(), &signature), Ok(true));
+	}
+
+	#[test]
+	fn converts_high_bit_signatures() {
+		check(&P256_HIGH_BIT, Curve::Nistp256);
+	}
+
+	#[test]
+	fn converts_short_signatures() {
+		check(&P256_SHORT_R, Curve::Nistp256);
+	}
+
+	#[test]
+	fn converts_p521_signatures() {
+		check(&P521, Curve::Nistp521);
+	}
+
+	#[test]
+	fn rejects_malformed_der_integers() {
+		// r negative, then r with a zero byte it doesn't need
+		for der in &["3006020180020101", "300702020001020101"] {
+			assert!(Signature::from_ecdsa_der(Curve::Nistp256, hex::decode(der).unwrap()).is_err(), "{} was accepted", der);
+		}
+	}
+}
@@ -7,10 +7,12 @@ use core_foundation::base::{TCFType, CFType, CFTypeRef, OSStatus};
 use core_foundation::data::{CFData, CFDataRef};
 use core_foundation::base::{ kCFAllocatorDefault, CFAllocatorRef};
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ptr;
 
-pub static PRIVATE_KEY_NAME: &'static str = "com.sekey.priv";
-pub static PUBLIC_KEY_NAME: &'static str = "com.sekey.pub";
+use key::{KeyId, PublicKey};
+use store::PubKey;
 
 static ERR_SEC_DUPLICATE_ITEM: OSStatus = -25299;
 static ERR_SEC_SUCCESS: OSStatus = 0;
@@ -135,17 +137,44 @@ impl CFDict {
 }
 
 
-#[derive(Debug)]
-pub struct PubKey {
-    pub label: String,
-    pub hash: Vec<u8>,
-    pub key: Vec<u8>,
+// tags identify the sekey items among everything else in the Keychain,
+// changing them hides the keys generated with the previous ones
+pub struct Keychain {
+    private_tag: String,
+    public_tag: String,
+    prompt: String,
+    // private key references already looked up, by key ID. Holding a reference doesn't
+    // unlock anything, Touch ID is still asked for on every signature.
+    private_refs: RefCell<HashMap<Vec<u8>, CFType>>,
 }
 
-pub struct Keychain;
+// the references are only used by whoever holds the agent lock, one thread at a time
+unsafe impl Send for Keychain {}
 
 impl Keychain {
 
+    pub fn new(private_tag: &str, public_tag: &str, prompt: &str) -> Self {
+        Self {
+            private_tag: private_tag.to_string(),
+            public_tag: public_tag.to_string(),
+            prompt: prompt.to_string(),
+            private_refs: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn forget_refs(&self) {
+        self.private_refs.borrow_mut().clear();
+    }
+
+    unsafe fn cached_private_ref(&self, hash: Vec<u8>) -> Result<CFTypeRef, &'static str> {
+        if let Some(keyref) = self.private_refs.borrow().get(&hash) {
+            return Ok(keyref.as_CFTypeRef());
+        }
+        let keyref = self.get_private_ref(hash.clone())?;
+        self.private_refs.borrow_mut().insert(hash, CFType::wrap_under_get_rule(keyref));
+        Ok(keyref)
+    }
+
     pub unsafe fn sec_item_copy_matching(dict: CFDictionary) -> Vec<CFDictionary>{
             let mut items = vec![];
 
@@ -174,7 +203,8 @@ impl Keychain {
             items       
     }
 
-    fn get_pubkey_from_cfdictionary(key: CFDictionary) -> PubKey {
+    // the point has to be one we can use, whatever the keychain hands back
+    fn get_pubkey_from_cfdictionary(key: CFDictionary) -> Result<PubKey, &'static str> {
         let label;
         let key_id;
         let key_data;
@@ -199,19 +229,19 @@ impl Keychain {
                                     CFData::wrap_under_get_rule(keydata as *const _).to_vec()
                                 })
                                 .unwrap_or_else(|| Vec::new())
-                        }).unwrap();
+                        }).unwrap_or_else(|| Vec::new());
         }
-        PubKey { label: label, hash: key_id, key: key_data }
+        Ok(PubKey { label: label, id: KeyId::new(key_id), key: PublicKey::from_sec1(&key_data)? })
     }
 
-    pub fn get_public_keys() -> Vec<PubKey> {
+    pub fn get_public_keys(&self) -> Vec<PubKey> {
         let mut pub_keys = Vec::new();
         unsafe {
             // create the query to ask the keychaing.
             let dict  = CFDict::new()
                 .add_string_ref(kSecClass, kSecClassKey)
                 .add_string_ref(kSecAttrKeyType, kSecAttrKeyTypeEC)
-                .add_label(kSecAttrApplicationTag, PUBLIC_KEY_NAME)
+                .add_label(kSecAttrApplicationTag, &self.public_tag)
                 .add_string_ref(kSecAttrKeyClass, kSecAttrKeyClassPublic)
                 .add_boolean(kSecReturnRef, true)
                 .add_string_ref(kSecMatchLimit, kSecMatchLimitAll)
@@ -221,19 +251,22 @@ impl Keychain {
             let keys = Keychain::sec_item_copy_matching(dict);
             // iter thru the keys and the get information from the key dict.
             for key in keys {
-                pub_keys.push(Keychain::get_pubkey_from_cfdictionary(key));
+                match Keychain::get_pubkey_from_cfdictionary(key) {
+                    Ok(key) => pub_keys.push(key),
+                    Err(err) => warn!("Skipping keychain key: {}", err),
+                }
             }
 
         }
         pub_keys
     }
 
-    unsafe fn get_public_ref(hash:Vec<u8>)-> Result<CFTypeRef, &'static str>{
+    unsafe fn get_public_ref(&self, hash:Vec<u8>)-> Result<CFTypeRef, &'static str>{
         let data = CFData::from_buffer(hash.as_slice());
         let dict  = CFDict::new()
                 .add_string_ref(kSecClass, kSecClassKey)
                 .add_string_ref(kSecAttrKeyType, kSecAttrKeyTypeEC)
-                .add_label(kSecAttrApplicationTag, PUBLIC_KEY_NAME)
+                .add_label(kSecAttrApplicationTag, &self.public_tag)
                 .add_cfdata(kSecAttrApplicationLabel, data)
                 .add_string_ref(kSecAttrKeyClass, kSecAttrKeyClassPublic)
                 .add_boolean(kSecReturnRef, true)
@@ -247,26 +280,26 @@ impl Keychain {
         }
     }
 
-    pub fn get_public_key(hash:Vec<u8>) -> Result<PubKey, &'static str> {
+    pub fn get_public_key(&self, hash:Vec<u8>) -> Result<PubKey, &'static str> {
         let key:PubKey;
         unsafe {
-            let keyref = Keychain::get_public_ref(hash)?;
+            let keyref = self.get_public_ref(hash)?;
             let keyref = CFDictionary::wrap_under_get_rule(keyref as CFDictionaryRef);
-            key = Keychain::get_pubkey_from_cfdictionary(keyref);
+            key = Keychain::get_pubkey_from_cfdictionary(keyref)?;
         }
 
         Ok(key)
     }
 
-    unsafe fn get_private_ref(hash:Vec<u8>)-> Result<CFTypeRef, &'static str>{
+    unsafe fn get_private_ref(&self, hash:Vec<u8>)-> Result<CFTypeRef, &'static str>{
         let data = CFData::from_buffer(hash.as_slice());
         let dict  = CFDict::new()
             .add_string_ref(kSecClass, kSecClassKey)
-            .add_label(kSecAttrLabel, PRIVATE_KEY_NAME)
+            .add_label(kSecAttrLabel, &self.private_tag)
             .add_string_ref(kSecAttrKeyClass, kSecAttrKeyClassPrivate)
             .add_cfdata(kSecAttrApplicationLabel, data)
             .add_boolean(kSecReturnRef, true)
-            .add_label(kSecUseOperationPrompt, "Authenticate to Sign Data")
+            .add_label(kSecUseOperationPrompt, &self.prompt)
             .get();
 
             let mut keys = Keychain::sec_item_copy_matching(dict);
@@ -279,15 +312,17 @@ impl Keychain {
 
     }
 
-    pub fn sign_data(data: Vec<u8>, key_hash: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+    pub fn sign_data(&self, data: Vec<u8>, key_hash: Vec<u8>) -> Result<Vec<u8>, &'static str> {
         let retdata: Vec<u8>;
         unsafe {
             let data = CFData::from_buffer(data.as_slice());
-            let keyref = Keychain::get_private_ref(key_hash)?;
+            let keyref = self.cached_private_ref(key_hash.clone())?;
             let mut err = ptr::null();
             let data = SecKeyCreateSignature(keyref, kSecKeyAlgorithmECDSASignatureMessageX962SHA256, data.as_concrete_TypeRef(), &mut err);
             
             if !err.is_null(){
+                // the key may be gone, look it up again next time
+                self.private_refs.borrow_mut().remove(&key_hash);
                 return Err("Error trying to sign data");
             }
             retdata = CFData::wrap_under_get_rule(data as *const _).to_vec()
@@ -295,11 +330,11 @@ impl Keychain {
         Ok(retdata)
     }
 
-    unsafe fn delete_private_key(hash:Vec<u8>){
+    unsafe fn delete_private_key(&self, hash:Vec<u8>){
         let data = CFData::from_buffer(hash.as_slice());
         let dict  = CFDict::new()
             .add_string_ref(kSecClass, kSecClassKey)
-            .add_label(kSecAttrLabel, PRIVATE_KEY_NAME)
+            .add_label(kSecAttrLabel, &self.private_tag)
             .add_string_ref(kSecAttrKeyClass, kSecAttrKeyClassPrivate)
             .add_cfdata(kSecAttrApplicationLabel, data)
             .add_boolean(kSecReturnRef, true)
@@ -312,12 +347,12 @@ impl Keychain {
 
     }
 
-    unsafe fn delete_public_key(hash:Vec<u8>){
+    unsafe fn delete_public_key(&self, hash:Vec<u8>){
         let data = CFData::from_buffer(hash.as_slice());
         let dict  = CFDict::new()
             .add_string_ref(kSecClass, kSecClassKey)
             .add_string_ref(kSecAttrKeyType, kSecAttrKeyTypeEC)
-            .add_label(kSecAttrApplicationTag, PUBLIC_KEY_NAME)
+            .add_label(kSecAttrApplicationTag, &self.public_tag)
             .add_cfdata(kSecAttrApplicationLabel, data)
             .add_string_ref(kSecAttrKeyClass, kSecAttrKeyClassPublic)
             .add_boolean(kSecReturnRef, true)
@@ -329,20 +364,21 @@ impl Keychain {
         }
     }
 
-    pub fn delete_keypair(hash:Vec<u8>) -> Result<(), &'static str> {
+    pub fn delete_keypair(&self, hash:Vec<u8>) -> Result<(), &'static str> {
+        self.private_refs.borrow_mut().remove(&hash);
         unsafe {
-            Keychain::delete_private_key(hash.clone());
-            Keychain::delete_public_key(hash);
+            self.delete_private_key(hash.clone());
+            self.delete_public_key(hash);
         }
         Ok(())
     }
 
-    unsafe fn save_public_key(key: CFTypeRef, label: String) {
+    unsafe fn save_public_key(&self, key: CFTypeRef, label: String) {
         let save_key_dict  = CFDict::new()
             .add_string_ref(kSecClass, kSecClassKey)
             .add_string_ref(kSecAttrKeyType, kSecAttrKeyTypeEC)
             .add_string_ref(kSecAttrKeyClass, kSecAttrKeyClassPublic)
-            .add_label(kSecAttrApplicationTag, PUBLIC_KEY_NAME)
+            .add_label(kSecAttrApplicationTag, &self.public_tag)
             .add_cftyperef(kSecValueRef, key)
             .add_boolean(kSecAttrIsPermanent, true)
             .add_boolean(kSecReturnData, true)
@@ -359,7 +395,7 @@ impl Keychain {
             SecItemAdd(save_key_dict.as_concrete_TypeRef(), &mut key_bits); 
     }
 
-    pub fn generate_keypair(label: String) -> Result<(), &'static str>{
+    pub fn generate_keypair(&self, label: String) -> Result<(), &'static str>{
         unsafe {
             let mut error:CFTypeRef = ptr::null();
 
@@ -375,13 +411,13 @@ impl Keychain {
             }
             
             let access_control_dict  = CFDict::new()
-                .add_label(kSecAttrLabel, PRIVATE_KEY_NAME)
+                .add_label(kSecAttrLabel, &self.private_tag)
                 .add_boolean(kSecAttrIsPermanent, true)
                 .add_cftyperef(kSecAttrAccessControl, access_control)
                 .get();
 
             let gen_pair_dict  = CFDict::new()
-                .add_label(kSecAttrLabel, PRIVATE_KEY_NAME)
+                .add_label(kSecAttrLabel, &self.private_tag)
                 .add_string_ref(kSecAttrTokenID, kSecAttrTokenIDSecureEnclave)
                 .add_string_ref(kSecAttrKeyType, kSecAttrKeyTypeEC)
                 .add_cfdict(kSecPrivateKeyAttrs, access_control_dict)
@@ -397,7 +433,7 @@ impl Keychain {
             if status != ERR_SEC_SUCCESS {
                 return Err("Error creating keypair")
             }
-            Keychain::save_public_key(public_key_ref, label);
+            self.save_public_key(public_key_ref, label);
             
         }
         Ok(())
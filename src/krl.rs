@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use openssl::sha::sha256;
+
+use cert::{self, Certificate};
+use key::KeyId;
+use store::KeyStore;
+use wire::{write_string, Reader};
+
+// "SSHKRL\n\0"
+const KRL_MAGIC: u64 = 0x5353484b524c0a00;
+const KRL_FORMAT_VERSION: u32 = 1;
+
+const SECTION_CERTIFICATES: u8 = 1;
+const SECTION_EXPLICIT_KEY: u8 = 2;
+const SECTION_FINGERPRINT_SHA1: u8 = 3;
+const SECTION_SIGNATURE: u8 = 4;
+const SECTION_FINGERPRINT_SHA256: u8 = 5;
+
+const CERT_SERIAL_LIST: u8 = 0x20;
+const CERT_SERIAL_RANGE: u8 = 0x21;
+const CERT_SERIAL_BITMAP: u8 = 0x22;
+const CERT_KEY_ID: u8 = 0x23;
+
+// certificates a CA revoked, by serial and by key ID
+#[derive(Debug, Clone, Default)]
+pub struct RevokedCerts {
+	// inclusive ranges, sorted and merged
+	pub serials: Vec<(u64, u64)>,
+	pub key_ids: BTreeSet<String>,
+}
+
+impl RevokedCerts {
+
+	pub fn revoke_serials(&mut self, low: u64, high: u64) {
+		self.serials.push((low, high));
+		self.merge();
+	}
+
+	// sorts the ranges and joins the ones that overlap or touch
+	fn merge(&mut self) {
+		self.serials.sort_unstable();
+		let mut merged: Vec<(u64, u64)> = vec![];
+		for &(low, high) in &self.serials {
+			match merged.last_mut() {
+				Some(last) if low <= last.1.saturating_add(1) => last.1 = last.1.max(high),
+				_ => merged.push((low, high)),
+			}
+		}
+		self.serials = merged;
+	}
+
+	pub fn is_serial_revoked(&self, serial: u64) -> bool {
+		self.serials.iter().any(|&(low, high)| low <= serial && serial <= high)
+	}
+
+	fn is_empty(&self) -> bool {
+		self.serials.is_empty() && self.key_ids.is_empty()
+	}
+}
+
+// adds a single serial, growing the last range when it follows on from it, so a run of
+// set bits in a bitmap takes one range rather than one per bit
+fn push_serial(serials: &mut Vec<(u64, u64)>, serial: u64) {
+	match serials.last_mut() {
+		Some(last) if last.0 <= serial && serial <= last.1.saturating_add(1) => last.1 = last.1.max(serial),
+		_ => serials.push((serial, serial)),
+	}
+}
+
+// OpenSSH key revocation list, see PROTOCOL.krl in the OpenSSH sources. sshd reads it
+// through RevokedKeys, ssh-keygen -Q checks keys against it.
+#[derive(Debug, Clone, Default)]
+pub struct Krl {
+	pub version: u64,
+	pub generated: u64,
+	pub comment: String,
+	// revoked certificates by CA key blob, an empty blob stands for any CA
+	pub certificates: BTreeMap<Vec<u8>, RevokedCerts>,
+	pub keys: BTreeSet<Vec<u8>>,
+	pub sha1: BTreeSet<Vec<u8>>,
+	pub sha256: BTreeSet<Vec<u8>>,
+	// signing key and signature, any change to the KRL drops them
+	pub signatures: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Krl {
+
+	pub fn new() -> Self {
+		Krl::default()
+	}
+
+	pub fn parse(blob: &[u8]) -> Result<Krl, &'static str> {
+		Krl::read(blob).map_err(|_| "Invalid KRL")
+	}
+
+	fn read(blob: &[u8]) -> Result<Krl, &'static str> {
+		let mut reader = Reader::unbounded(blob);
+		if reader.read_u64()? != KRL_MAGIC || reader.read_u32()? != KRL_FORMAT_VERSION {
+			return Err("Not a KRL");
+		}
+		let mut krl = Krl::new();
+		krl.version = reader.read_u64()?;
+		krl.generated = reader.read_u64()?;
+		// flags
+		reader.read_u64()?;
+		// reserved
+		reader.read_string()?;
+		krl.comment = String::from_utf8_lossy(reader.read_string()?).into_owned();
+
+		while reader.remaining() > 0 {
+			let section = reader.read_u8()?;
+			if section == SECTION_SIGNATURE {
+				let key = reader.read_string()?.to_vec();
+				let signature = reader.read_string()?.to_vec();
+				krl.signatures.push((key, signature));
+				continue;
+			}
+			// signatures cover everything before them, so they come last
+			if !krl.signatures.is_empty() {
+				return Err("Section after a KRL signature");
+			}
+			let data = reader.read_string()?;
+			match section {
+				SECTION_CERTIFICATES => krl.read_certificates(data)?,
+				SECTION_EXPLICIT_KEY => read_blobs(data, &mut krl.keys)?,
+				SECTION_FINGERPRINT_SHA1 => read_blobs(data, &mut krl.sha1)?,
+				SECTION_FINGERPRINT_SHA256 => read_blobs(data, &mut krl.sha256)?,
+				_ => return Err("Unknown KRL section"),
+			}
+		}
+		Ok(krl)
+	}
+
+	fn read_certificates(&mut self, data: &[u8]) -> Result<(), &'static str> {
+		let mut reader = Reader::unbounded(data);
+		let ca = reader.read_string()?.to_vec();
+		// reserved
+		reader.read_string()?;
+		let revoked = self.certificates.entry(ca).or_default();
+
+		// merged once at the end, KRLs may list serials by the hundred thousand
+		let mut serials = vec![];
+		while reader.remaining() > 0 {
+			let kind = reader.read_u8()?;
+			let mut fields = Reader::unbounded(reader.read_string()?);
+			match kind {
+				CERT_SERIAL_LIST => while fields.remaining() > 0 {
+					push_serial(&mut serials, fields.read_u64()?);
+				},
+				CERT_SERIAL_RANGE => {
+					let low = fields.read_u64()?;
+					let high = fields.read_u64()?;
+					if low > high {
+						return Err("Empty serial range in KRL");
+					}
+					serials.push((low, high));
+				}
+				// bit n of the bitmap revokes serial offset + n
+				CERT_SERIAL_BITMAP => {
+					let offset = fields.read_u64()?;
+					let bitmap = fields.read_mpint()?;
+					for (index, byte) in bitmap.iter().rev().enumerate() {
+						for bit in 0..8 {
+							if byte & (1 << bit) != 0 {
+								push_serial(&mut serials, offset.saturating_add(index as u64 * 8 + bit));
+							}
+						}
+					}
+				}
+				CERT_KEY_ID => while fields.remaining() > 0 {
+					revoked.key_ids.insert(String::from_utf8_lossy(fields.read_string()?).into_owned());
+				},
+				_ => return Err("Unknown KRL certificate section"),
+			}
+		}
+		revoked.serials.extend(serials);
+		revoked.merge();
+		Ok(())
+	}
+
+	// a new version, the signatures no longer match
+	pub fn touch(&mut self, now: u64) {
+		self.version += 1;
+		self.generated = now;
+		self.signatures.clear();
+	}
+
+	pub fn revoke_serials(&mut self, ca: &[u8], low: u64, high: u64) {
+		self.certificates.entry(ca.to_vec()).or_default().revoke_serials(low, high);
+	}
+
+	pub fn revoke_key_id(&mut self, ca: &[u8], key_id: &str) {
+		self.certificates.entry(ca.to_vec()).or_default().key_ids.insert(key_id.to_string());
+	}
+
+	// certificates are revoked by serial under their CA like ssh-keygen -k does, by key
+	// ID when they have no serial. Plain keys are revoked outright.
+	pub fn revoke_key(&mut self, blob: &[u8]) -> Result<(), &'static str> {
+		if !cert::is_certificate(blob) {
+			self.keys.insert(blob.to_vec());
+			return Ok(());
+		}
+		let certificate = Certificate::parse(blob)?;
+		if certificate.serial != 0 {
+			self.revoke_serials(&certificate.signature_key, certificate.serial, certificate.serial);
+		} else {
+			self.revoke_key_id(&certificate.signature_key, &certificate.key_id);
+		}
+		Ok(())
+	}
+
+	// why `blob`, a key or a certificate, is revoked, None when it isn't
+	pub fn revocation(&self, blob: &[u8]) -> Option<String> {
+		if !cert::is_certificate(blob) {
+			return self.key_revocation(blob).map(|reason| format!("key {}", reason));
+		}
+		let certificate = match Certificate::parse(blob) {
+			Ok(certificate) => certificate,
+			Err(err) => return Some(err.to_string()),
+		};
+		if let Some(reason) = self.key_revocation(&certificate.subject_key()) {
+			return Some(format!("certified key {}", reason));
+		}
+		if let Some(reason) = self.key_revocation(&certificate.signature_key) {
+			return Some(format!("CA key {}", reason));
+		}
+		for ca in &[certificate.signature_key.clone(), vec![]] {
+			if let Some(revoked) = self.certificates.get(ca) {
+				if revoked.is_serial_revoked(certificate.serial) {
+					return Some(format!("serial {} revoked", certificate.serial));
+				}
+				if revoked.key_ids.contains(&certificate.key_id) {
+					return Some(format!("key ID \"{}\" revoked", certificate.key_id));
+				}
+			}
+		}
+		None
+	}
+
+	fn key_revocation(&self, blob: &[u8]) -> Option<&'static str> {
+		if self.keys.contains(blob) {
+			return Some("revoked");
+		}
+		let mut hasher = Sha1::new();
+		let mut sha1 = [0; 20];
+		hasher.input(blob);
+		hasher.result(&mut sha1);
+		if self.sha1.contains(&sha1[..]) || self.sha256.contains(&sha256(blob)[..]) {
+			return Some("revoked by fingerprint");
+		}
+		None
+	}
+
+	// everything up to the signatures
+	fn unsigned(&self) -> io::Result<Vec<u8>> {
+		let mut data = vec![];
+		data.write_u64::<BigEndian>(KRL_MAGIC)?;
+		data.write_u32::<BigEndian>(KRL_FORMAT_VERSION)?;
+		data.write_u64::<BigEndian>(self.version)?;
+		data.write_u64::<BigEndian>(self.generated)?;
+		// flags
+		data.write_u64::<BigEndian>(0)?;
+		// reserved
+		write_string(&mut data, &[])?;
+		write_string(&mut data, self.comment.as_bytes())?;
+
+		for (ca, revoked) in &self.certificates {
+			if revoked.is_empty() {
+				continue;
+			}
+			data.write_u8(SECTION_CERTIFICATES)?;
+			write_string(&mut data, &write_certificates(ca, revoked)?)?;
+		}
+		for (section, blobs) in [
+			(SECTION_EXPLICIT_KEY, &self.keys),
+			(SECTION_FINGERPRINT_SHA1, &self.sha1),
+			(SECTION_FINGERPRINT_SHA256, &self.sha256),
+		] {
+			if blobs.is_empty() {
+				continue;
+			}
+			let mut section_data = vec![];
+			for blob in blobs.iter() {
+				write_string(&mut section_data, blob)?;
+			}
+			data.write_u8(section)?;
+			write_string(&mut data, &section_data)?;
+		}
+		Ok(data)
+	}
+
+	pub fn to_blob(&self) -> Vec<u8> {
+		let mut blob = self.unsigned().unwrap_or_default();
+		for (key, signature) in &self.signatures {
+			blob.push(SECTION_SIGNATURE);
+			write_string(&mut blob, key).unwrap();
+			write_string(&mut blob, signature).unwrap();
+		}
+		blob
+	}
+
+	// the signature covers the whole KRL up to and including the signing key
+	pub fn sign(&mut self, store: &mut KeyStore, ca_id: &KeyId) -> Result<(), &'static str> {
+		let ca = store.get_public_key(ca_id).ok_or("CA key not found")?.blob();
+		self.signatures.clear();
+		let mut data = self.unsigned().map_err(|_| "Error encoding KRL")?;
+		data.push(SECTION_SIGNATURE);
+		write_string(&mut data, &ca).unwrap();
+		let signature = store.ssh_signature(ca_id, &data)?.to_blob();
+		self.signatures.push((ca, signature));
+		Ok(())
+	}
+}
+
+// single serials go in one list, ranges get a section each
+fn write_certificates(ca: &[u8], revoked: &RevokedCerts) -> io::Result<Vec<u8>> {
+	let mut data = vec![];
+	write_string(&mut data, ca)?;
+	// reserved
+	write_string(&mut data, &[])?;
+
+	let mut list = vec![];
+	for &(low, high) in &revoked.serials {
+		if low == high {
+			list.write_u64::<BigEndian>(low)?;
+		} else {
+			let mut range = vec![];
+			range.write_u64::<BigEndian>(low)?;
+			range.write_u64::<BigEndian>(high)?;
+			data.write_u8(CERT_SERIAL_RANGE)?;
+			write_string(&mut data, &range)?;
+		}
+	}
+	if !list.is_empty() {
+		data.write_u8(CERT_SERIAL_LIST)?;
+		write_string(&mut data, &list)?;
+	}
+	if !revoked.key_ids.is_empty() {
+		let mut key_ids = vec![];
+		for key_id in &revoked.key_ids {
+			write_string(&mut key_ids, key_id.as_bytes())?;
+		}
+		data.write_u8(CERT_KEY_ID)?;
+		write_string(&mut data, &key_ids)?;
+	}
+	Ok(data)
+}
+
+fn read_blobs(data: &[u8], blobs: &mut BTreeSet<Vec<u8>>) -> Result<(), &'static str> {
+	let mut reader = Reader::unbounded(data);
+	while reader.remaining() > 0 {
+		blobs.insert(reader.read_string()?.to_vec());
+	}
+	Ok(())
+}
+
+// "42" or "1-100", serial 0 stands for certificates without a serial and can't be revoked
+pub fn parse_serials(serials: &str) -> Result<(u64, u64), String> {
+	let invalid = || format!("Invalid serial range \"{}\"", serials);
+	let (low, high) = match serials.find('-') {
+		Some(position) => (&serials[..position], &serials[position + 1..]),
+		None => (serials, serials),
+	};
+	let low = low.trim().parse::<u64>().map_err(|_| invalid())?;
+	let high = high.trim().parse::<u64>().map_err(|_| invalid())?;
+	if low == 0 || low > high {
+		return Err(invalid());
+	}
+	Ok((low, high))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use byteorder::ByteOrder;
+
+	use wire::Writer;
+
+	fn u64_bytes(value: u64) -> [u8; 8] {
+		let mut bytes = [0; 8];
+		BigEndian::write_u64(&mut bytes, value);
+		bytes
+	}
+
+	// a KRL with a single certificates section, for any CA
+	fn krl_with(entries: &[(u8, Vec<u8>)]) -> Vec<u8> {
+		let mut section = Writer::new();
+		section.write_string(b"");
+		section.write_string(b"");
+		for &(kind, ref fields) in entries {
+			section.write_u8(kind);
+			section.write_string(fields);
+		}
+		let mut krl = Writer::new();
+		krl.write_raw(&u64_bytes(KRL_MAGIC));
+		krl.write_u32(KRL_FORMAT_VERSION);
+		krl.write_raw(&u64_bytes(1));
+		krl.write_raw(&u64_bytes(0));
+		krl.write_raw(&u64_bytes(0));
+		krl.write_string(b"");
+		krl.write_string(b"");
+		krl.write_u8(SECTION_CERTIFICATES);
+		krl.write_string(&section.into_vec());
+		krl.into_vec()
+	}
+
+	fn bitmap(offset: u64, bitmap: &[u8]) -> Vec<u8> {
+		let mut fields = Writer::new();
+		fields.write_raw(&u64_bytes(offset));
+		fields.write_mpint(bitmap);
+		fields.into_vec()
+	}
+
+	#[test]
+	fn merges_serials_of_every_kind() {
+		let list: Vec<u8> = [10, 1, 2, 3].iter().flat_map(|&serial| u64_bytes(serial).to_vec()).collect();
+		let range = [u64_bytes(4), u64_bytes(8)].concat();
+		let krl = Krl::parse(&krl_with(&[
+			(CERT_SERIAL_LIST, list),
+			(CERT_SERIAL_RANGE, range),
+			// serials 100, 101, 103, 105 and 106
+			(CERT_SERIAL_BITMAP, bitmap(100, &[0b0110_1011])),
+		])).unwrap();
+		assert_eq!(krl.certificates[&vec![]].serials, vec![(1, 8), (10, 10), (100, 101), (103, 103), (105, 106)]);
+	}
+
+	#[test]
+	fn parses_large_bitmaps() {
+		// every other serial, which takes a range each
+		let krl = Krl::parse(&krl_with(&[(CERT_SERIAL_BITMAP, bitmap(0, &[0x55; 64 * 1024]))])).unwrap();
+		let revoked = &krl.certificates[&vec![]];
+		assert_eq!(revoked.serials.len(), 256 * 1024);
+		assert!(revoked.is_serial_revoked(2 * 1024));
+		assert!(!revoked.is_serial_revoked(2 * 1024 + 1));
+
+		let krl = Krl::parse(&krl_with(&[(CERT_SERIAL_BITMAP, bitmap(0, &[0xff; 64 * 1024]))])).unwrap();
+		assert_eq!(krl.certificates[&vec![]].serials, vec![(0, 512 * 1024 - 1)]);
+	}
+}
@@ -1,14 +1,53 @@
+// struct literals are written out as `field: field` throughout
+#![allow(clippy::redundant_field_names)]
+
+extern crate base64;
 extern crate byteorder;
+#[cfg(target_os = "macos")]
 extern crate core_foundation;
 extern crate libc;
-extern crate ssh_agent;
 #[macro_use]
 extern crate eagre_asn1;
 extern crate crypto;
+extern crate hex;
+#[macro_use]
+extern crate log;
+extern crate openssl;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 
 
+#[cfg(target_os = "macos")]
 mod keychain;
 pub mod ecdsa;
+pub mod ed25519;
 
+#[cfg(target_os = "macos")]
 pub use keychain::Keychain;
-pub mod handler;
\ No newline at end of file
+pub mod handler;
+pub mod agent;
+pub mod audit;
+pub mod authorized_keys;
+pub mod cert;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod events;
+pub mod export;
+pub mod fingerprint;
+pub mod home;
+pub mod install;
+pub mod key;
+pub mod krl;
+pub mod peer;
+pub mod policy;
+pub mod ratelimit;
+pub mod rsa;
+pub mod shell;
+pub mod software;
+pub mod sshsig;
+pub mod store;
+pub mod wire;
\ No newline at end of file
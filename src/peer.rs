@@ -0,0 +1,99 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use libc;
+
+// process on the other end of an agent connection, as far as the kernel tells us
+#[derive(Debug, Clone)]
+pub struct Peer {
+	pub pid: Option<libc::pid_t>,
+	pub uid: Option<libc::uid_t>,
+	pub exe: Option<PathBuf>,
+}
+
+impl Peer {
+
+	pub fn from_stream(stream: &UnixStream) -> Peer {
+		let fd = stream.as_raw_fd();
+		let pid = peer_pid(fd);
+		Peer {
+			pid: pid,
+			uid: peer_uid(fd),
+			exe: pid.and_then(exe_path),
+		}
+	}
+
+	// stable name for the peer, used to group requests coming from the same program
+	pub fn name(&self) -> String {
+		match (self.exe.as_ref(), self.pid) {
+			(Some(exe), _) => exe.display().to_string(),
+			(None, Some(pid)) => format!("pid:{}", pid),
+			(None, None) => String::from("unknown"),
+		}
+	}
+}
+
+#[cfg(target_os = "macos")]
+const PROC_PIDPATHINFO_MAXSIZE: usize = 4 * 1024;
+
+#[cfg(target_os = "macos")]
+extern "C" {
+	fn proc_pidpath(pid: libc::c_int, buffer: *mut libc::c_void, buffersize: u32) -> libc::c_int;
+}
+
+#[cfg(target_os = "macos")]
+fn peer_pid(fd: RawFd) -> Option<libc::pid_t> {
+	let mut pid: libc::pid_t = 0;
+	let mut len = ::std::mem::size_of::<libc::pid_t>() as libc::socklen_t;
+	let ret = unsafe {
+		libc::getsockopt(fd, libc::SOL_LOCAL, libc::LOCAL_PEERPID, &mut pid as *mut _ as *mut libc::c_void, &mut len)
+	};
+	if ret == 0 { Some(pid) } else { None }
+}
+
+#[cfg(target_os = "macos")]
+fn peer_uid(fd: RawFd) -> Option<libc::uid_t> {
+	let mut uid: libc::uid_t = 0;
+	let mut gid: libc::gid_t = 0;
+	let ret = unsafe { libc::getpeereid(fd, &mut uid, &mut gid) };
+	if ret == 0 { Some(uid) } else { None }
+}
+
+#[cfg(target_os = "macos")]
+fn exe_path(pid: libc::pid_t) -> Option<PathBuf> {
+	let mut buffer = vec![0u8; PROC_PIDPATHINFO_MAXSIZE];
+	let len = unsafe {
+		proc_pidpath(pid, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len() as u32)
+	};
+	if len <= 0 {
+		return None;
+	}
+	buffer.truncate(len as usize);
+	String::from_utf8(buffer).ok().map(PathBuf::from)
+}
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(fd: RawFd) -> Option<libc::ucred> {
+	let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+	let mut len = ::std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+	let ret = unsafe {
+		libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len)
+	};
+	if ret == 0 { Some(cred) } else { None }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_pid(fd: RawFd) -> Option<libc::pid_t> {
+	peer_credentials(fd).map(|cred| cred.pid)
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(fd: RawFd) -> Option<libc::uid_t> {
+	peer_credentials(fd).map(|cred| cred.uid)
+}
+
+#[cfg(target_os = "linux")]
+fn exe_path(pid: libc::pid_t) -> Option<PathBuf> {
+	::std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use toml;
+
+use ratelimit::{Limit, NotifyHook, RateLimiter};
+
+// the hook fires when `hook_threshold` requests are refused within this window
+static LIMIT_HOOK_WINDOW_SECS: u64 = 60;
+static LIMIT_HOOK_THRESHOLD: u32 = 5;
+
+// Contents of the policy file (`policy_file` in the config):
+//
+//     [limits]
+//     key = "10/m"          # sign requests for each key
+//     peer = "30/m"         # sign requests for each client executable
+//     global = "100/h"      # sign requests for the whole agent
+//     hook = "notify-send 'sekey' \"$SEKEY_LIMIT_PEER\""
+//     hook_threshold = 5    # refused requests within a minute before the hook runs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+	pub limits: LimitsPolicy,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsPolicy {
+	pub key: Option<String>,
+	pub peer: Option<String>,
+	pub global: Option<String>,
+	pub hook: Option<String>,
+	pub hook_threshold: Option<u32>,
+}
+
+impl Policy {
+
+	// a missing file means no policy at all
+	pub fn load(path: &Path) -> Result<Policy, String> {
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Policy::default()),
+			Err(err) => return Err(format!("Error reading {}: {}", path.display(), err)),
+		};
+		toml::from_str(&contents).map_err(|err| format!("{}: {}", path.display(), err))
+	}
+
+	pub fn check(&self) -> Vec<String> {
+		match self.limits.rate_limiter() {
+			Ok(_) => vec![],
+			Err(err) => vec![err],
+		}
+	}
+}
+
+impl LimitsPolicy {
+
+	// every limit set in `overrides` wins over ours
+	pub fn merge(self, overrides: &LimitsPolicy) -> LimitsPolicy {
+		LimitsPolicy {
+			key: overrides.key.clone().or(self.key),
+			peer: overrides.peer.clone().or(self.peer),
+			global: overrides.global.clone().or(self.global),
+			hook: overrides.hook.clone().or(self.hook),
+			hook_threshold: overrides.hook_threshold.or(self.hook_threshold),
+		}
+	}
+
+	pub fn rate_limiter(&self) -> Result<RateLimiter, String> {
+		let mut limiter = RateLimiter::new(
+			parse_limit("limits.global", &self.global)?,
+			parse_limit("limits.key", &self.key)?,
+			parse_limit("limits.peer", &self.peer)?,
+		);
+		if let Some(ref command) = self.hook {
+			let threshold = self.hook_threshold.unwrap_or(LIMIT_HOOK_THRESHOLD);
+			limiter.set_hook(NotifyHook::new(command.clone(), threshold, Duration::from_secs(LIMIT_HOOK_WINDOW_SECS)));
+		}
+		Ok(limiter)
+	}
+}
+
+fn parse_limit(name: &str, limit: &Option<String>) -> Result<Option<Limit>, String> {
+	match *limit {
+		Some(ref limit) => Limit::parse(limit)
+			.map(Some)
+			.map_err(|err| format!("{}: {}", name, err)),
+		None => Ok(None),
+	}
+}
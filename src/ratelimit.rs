@@ -0,0 +1,329 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use key::KeyId;
+use peer::Peer;
+
+// a limit written as "<count>/<period>", e.g. "10/60s", "100/h" or "5/2m"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+	pub count: u32,
+	pub period: Duration,
+}
+
+impl Limit {
+
+	pub fn parse(limit: &str) -> Result<Limit, &'static str> {
+		let mut parts = limit.splitn(2, '/');
+		let count = parts.next()
+			.and_then(|count| count.trim().parse::<u32>().ok())
+			.ok_or("Invalid limit count")?;
+		let period = parts.next().ok_or("Limit must look like <count>/<period>")?.trim();
+		if period.is_empty() {
+			return Err("Limit must look like <count>/<period>");
+		}
+
+		let (amount, unit) = period.split_at(period.find(|c: char| !c.is_ascii_digit()).unwrap_or(period.len()));
+		let amount = if amount.is_empty() { 1 } else {
+			amount.parse::<u64>().map_err(|_| "Invalid limit period")?
+		};
+		let unit_seconds = match unit {
+			"" | "s" => 1,
+			"m" => 60,
+			"h" => 60 * 60,
+			_ => return Err("Invalid limit period unit, use s, m or h"),
+		};
+		let seconds = amount.checked_mul(unit_seconds).ok_or("Limit period is too long")?;
+		if count == 0 || seconds == 0 {
+			return Err("Limit count and period must be greater than zero");
+		}
+		Ok(Limit { count: count, period: Duration::from_secs(seconds) })
+	}
+}
+
+struct TokenBucket {
+	capacity: f64,
+	tokens: f64,
+	refill_per_sec: f64,
+	last: Instant,
+}
+
+impl TokenBucket {
+
+	fn new(limit: Limit) -> Self {
+		let capacity = f64::from(limit.count);
+		Self {
+			capacity: capacity,
+			tokens: capacity,
+			refill_per_sec: capacity / duration_secs(limit.period),
+			last: Instant::now(),
+		}
+	}
+
+	fn refill(&mut self, now: Instant) {
+		let elapsed = duration_secs(now.duration_since(self.last));
+		self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+		self.last = now;
+	}
+
+	// back to full, the bucket is the same as a new one and can go
+	fn is_full(&self) -> bool {
+		self.tokens >= self.capacity
+	}
+
+	fn has_token(&self) -> bool {
+		self.tokens >= 1.0
+	}
+
+	fn take(&mut self) {
+		self.tokens -= 1.0;
+	}
+}
+
+// which of the limits refused the request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+	Global,
+	Key,
+	Peer,
+}
+
+impl Scope {
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Scope::Global => "global",
+			Scope::Key => "key",
+			Scope::Peer => "peer",
+		}
+	}
+}
+
+// command run through the shell once `threshold` requests were refused within `window`
+pub struct NotifyHook {
+	command: String,
+	threshold: usize,
+	window: Duration,
+	denials: VecDeque<Instant>,
+}
+
+impl NotifyHook {
+
+	pub fn new(command: String, threshold: u32, window: Duration) -> Self {
+		Self {
+			command: command,
+			threshold: threshold.max(1) as usize,
+			window: window,
+			denials: VecDeque::new(),
+		}
+	}
+
+	fn denied(&mut self, now: Instant, scope: Scope, key: &str, peer: &Peer) {
+		while self.denials.front().is_some_and(|first| now.duration_since(*first) > self.window) {
+			self.denials.pop_front();
+		}
+		self.denials.push_back(now);
+		if self.denials.len() < self.threshold {
+			return;
+		}
+		let count = self.denials.len();
+		// start counting again so the hook fires once per burst of refusals
+		self.denials.clear();
+
+		let spawned = Command::new("/bin/sh")
+			.arg("-c")
+			.arg(&self.command)
+			.env("SEKEY_LIMIT_SCOPE", scope.name())
+			.env("SEKEY_LIMIT_KEY", key)
+			.env("SEKEY_LIMIT_PEER", peer.name())
+			.env("SEKEY_LIMIT_DENIALS", count.to_string())
+			.spawn();
+		match spawned {
+			// reaped in the background so the hook doesn't linger as a zombie
+			Ok(mut child) => {
+				thread::spawn(move || child.wait());
+			}
+			Err(err) => warn!("Error running rate limit hook: {}", err),
+		}
+	}
+}
+
+pub struct RateLimiter {
+	global_limit: Option<Limit>,
+	global: Option<TokenBucket>,
+	key_limit: Option<Limit>,
+	keys: HashMap<KeyId, TokenBucket>,
+	peer_limit: Option<Limit>,
+	peers: HashMap<String, TokenBucket>,
+	hook: Option<NotifyHook>,
+}
+
+impl RateLimiter {
+
+	pub fn new(global: Option<Limit>, key: Option<Limit>, peer: Option<Limit>) -> Self {
+		Self {
+			global_limit: global,
+			global: global.map(TokenBucket::new),
+			key_limit: key,
+			keys: HashMap::new(),
+			peer_limit: peer,
+			peers: HashMap::new(),
+			hook: None,
+		}
+	}
+
+	pub fn unlimited() -> Self {
+		RateLimiter::new(None, None, None)
+	}
+
+	pub fn set_hook(&mut self, hook: NotifyHook) {
+		self.hook = Some(hook);
+	}
+
+	// takes over the buckets of `previous` whose limit didn't change, so reloading
+	// doesn't hand everybody a fresh allowance
+	pub fn carry_over(&mut self, previous: RateLimiter) {
+		if previous.global_limit == self.global_limit {
+			self.global = previous.global;
+		}
+		if previous.key_limit == self.key_limit {
+			self.keys = previous.keys;
+		}
+		if previous.peer_limit == self.peer_limit {
+			self.peers = previous.peers;
+		}
+	}
+
+	// takes a token from every bucket that applies, or from none of them
+	// if any bucket is empty, so a refused request doesn't eat into the others
+	pub fn check(&mut self, key_id: &KeyId, key_name: &str, peer: &Peer) -> Result<(), Scope> {
+		let now = Instant::now();
+
+		// idle keys and peers would otherwise pile up for as long as the daemon runs,
+		// clients can ask for any number of keys we don't even hold
+		if let Some(limit) = self.key_limit {
+			prune(&mut self.keys, now);
+			self.keys.entry(key_id.clone()).or_insert_with(|| TokenBucket::new(limit));
+		}
+		let peer_key = peer_key(peer);
+		if let Some(limit) = self.peer_limit {
+			prune(&mut self.peers, now);
+			self.peers.entry(peer_key.clone()).or_insert_with(|| TokenBucket::new(limit));
+		}
+		if let Some(ref mut global) = self.global {
+			global.refill(now);
+		}
+
+		let refused = if self.global.as_ref().is_some_and(|bucket| !bucket.has_token()) {
+			Some(Scope::Global)
+		} else if self.keys.get(key_id).is_some_and(|bucket| !bucket.has_token()) {
+			Some(Scope::Key)
+		} else if self.peers.get(&peer_key).is_some_and(|bucket| !bucket.has_token()) {
+			Some(Scope::Peer)
+		} else {
+			None
+		};
+
+		if let Some(scope) = refused {
+			if let Some(ref mut hook) = self.hook {
+				hook.denied(now, scope, key_name, peer);
+			}
+			return Err(scope);
+		}
+
+		if let Some(ref mut global) = self.global {
+			global.take();
+		}
+		if let Some(bucket) = self.keys.get_mut(key_id) {
+			bucket.take();
+		}
+		if let Some(bucket) = self.peers.get_mut(&peer_key) {
+			bucket.take();
+		}
+		Ok(())
+	}
+}
+
+// refills the buckets and drops the full ones, which are no different from new ones
+fn prune<K: Eq + Hash>(buckets: &mut HashMap<K, TokenBucket>, now: Instant) {
+	buckets.retain(|_, bucket| {
+		bucket.refill(now);
+		!bucket.is_full()
+	});
+}
+
+// the bucket a peer draws from: its executable, or its user when the executable is
+// unknown, since a pid changes with every connection
+fn peer_key(peer: &Peer) -> String {
+	match (peer.exe.as_ref(), peer.uid) {
+		(Some(exe), _) => exe.display().to_string(),
+		(None, Some(uid)) => format!("uid:{}", uid),
+		(None, None) => String::from("unknown"),
+	}
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+	duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_limits() {
+		assert_eq!(Limit::parse("10/60s"), Ok(Limit { count: 10, period: Duration::from_secs(60) }));
+		assert_eq!(Limit::parse("100/h"), Ok(Limit { count: 100, period: Duration::from_secs(3600) }));
+		assert_eq!(Limit::parse("5/2m"), Ok(Limit { count: 5, period: Duration::from_secs(120) }));
+		assert_eq!(Limit::parse("3/30"), Ok(Limit { count: 3, period: Duration::from_secs(30) }));
+	}
+
+	#[test]
+	fn carries_over_unchanged_limits() {
+		let hour = Some(Limit { count: 1, period: Duration::from_secs(3600) });
+		let key = KeyId::new(vec![1]);
+		let peer = Peer { pid: Some(1), uid: Some(1), exe: None };
+
+		let mut limiter = RateLimiter::new(hour, None, None);
+		assert_eq!(limiter.check(&key, "key", &peer), Ok(()));
+
+		let mut reloaded = RateLimiter::new(hour, None, None);
+		reloaded.carry_over(limiter);
+		assert_eq!(reloaded.check(&key, "key", &peer), Err(Scope::Global));
+
+		let mut changed = RateLimiter::new(Some(Limit { count: 2, period: Duration::from_secs(3600) }), None, None);
+		changed.carry_over(reloaded);
+		assert_eq!(changed.check(&key, "key", &peer), Ok(()));
+	}
+
+	#[test]
+	fn forgets_idle_keys() {
+		let limit = Some(Limit { count: 1, period: Duration::from_secs(3600) });
+		let peer = Peer { pid: Some(1), uid: Some(1), exe: None };
+
+		let mut limiter = RateLimiter::new(None, limit, None);
+		for id in 0..100 {
+			assert_eq!(limiter.check(&KeyId::new(vec![id]), "key", &peer), Ok(()));
+		}
+		assert_eq!(limiter.keys.len(), 100);
+		assert_eq!(limiter.check(&KeyId::new(vec![0]), "key", &peer), Err(Scope::Key));
+
+		// a bucket refilled to the brim is dropped, the key starts over with a new one
+		let mut limiter = RateLimiter::new(None, Some(Limit { count: 1000, period: Duration::from_secs(1) }), None);
+		for id in 0..100 {
+			assert_eq!(limiter.check(&KeyId::new(vec![id]), "key", &peer), Ok(()));
+		}
+		thread::sleep(Duration::from_millis(10));
+		assert_eq!(limiter.check(&KeyId::new(vec![0]), "key", &peer), Ok(()));
+		assert_eq!(limiter.keys.len(), 1);
+	}
+
+	#[test]
+	fn rejects_invalid_limits() {
+		for limit in &["10", "10/", "10/ ", "/m", "0/m", "10/0s", "10/d", "-1/m", "10/18446744073709551615h"] {
+			assert!(Limit::parse(limit).is_err(), "{} was accepted", limit);
+		}
+	}
+}
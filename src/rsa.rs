@@ -0,0 +1,29 @@
+use wire::{Reader, Writer};
+
+pub static RSA_TYPE: &str = "ssh-rsa";
+// signature algorithms of RSA keys, RFC 8332. ssh-rsa itself signs with SHA-1.
+pub static RSA_SHA2_256: &str = "rsa-sha2-256";
+pub static RSA_SHA2_512: &str = "rsa-sha2-512";
+
+pub struct Rsa;
+impl Rsa {
+
+	// write to SSH-Key Format: key type, public exponent and modulus
+	pub fn write(e: &[u8], n: &[u8]) -> Vec<u8> {
+		let mut writer = Writer::new();
+		writer.write_string(RSA_TYPE.as_bytes());
+		writer.write_mpint(e);
+		writer.write_mpint(n);
+		writer.into_vec()
+	}
+
+	// read from SSH-Key Format: the public exponent and the modulus
+	pub fn read(data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), &'static str> {
+		let mut reader = Reader::new(data);
+		reader.expect_string(RSA_TYPE.as_bytes(), "Not an RSA key")?;
+		let e = reader.read_mpint()?.to_vec();
+		let n = reader.read_mpint()?.to_vec();
+		reader.finish()?;
+		Ok((e, n))
+	}
+}
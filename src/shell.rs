@@ -0,0 +1,53 @@
+use std::env;
+
+// flavours of the environment commands printed for `eval`, same as `ssh-agent -s`/`-c`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+	Sh,
+	Csh,
+	Fish,
+}
+
+impl Shell {
+
+	// guess from $SHELL, the way ssh-agent does when neither -s nor -c is given
+	pub fn detect() -> Shell {
+		let shell = env::var("SHELL").unwrap_or_default();
+		if shell.ends_with("csh") {
+			Shell::Csh
+		} else if shell.ends_with("fish") {
+			Shell::Fish
+		} else {
+			Shell::Sh
+		}
+	}
+
+	pub fn export(&self, name: &str, value: &str) -> String {
+		let value = quote(value);
+		match *self {
+			Shell::Sh => format!("{}={}; export {};\n", name, value, name),
+			Shell::Csh => format!("setenv {} {};\n", name, value),
+			Shell::Fish => format!("set -x {} {};\n", name, value),
+		}
+	}
+
+	pub fn unset(&self, name: &str) -> String {
+		match *self {
+			Shell::Sh => format!("unset {};\n", name),
+			Shell::Csh => format!("unsetenv {};\n", name),
+			Shell::Fish => format!("set -e {};\n", name),
+		}
+	}
+}
+
+// plain paths are printed as is, like ssh-agent does, anything else is single quoted
+fn quote(value: &str) -> String {
+	let plain = !value.is_empty() && value.chars().all(|c| {
+		c.is_ascii_alphanumeric() || "/._-+:@%,".contains(c)
+	});
+	if plain {
+		value.to_string()
+	} else {
+		format!("'{}'", value.replace('\'', "'\\''"))
+	}
+}
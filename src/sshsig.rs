@@ -0,0 +1,458 @@
+use std::io::{self, Read};
+use std::mem;
+
+use base64;
+use libc;
+use openssl::hash::{Hasher, MessageDigest};
+
+use authorized_keys::split_options;
+use cert;
+use key::{KeyId, PublicKey, Signature};
+use rsa::RSA_TYPE;
+use store::KeyStore;
+use wire::{Reader, Writer};
+
+// signatures of ssh-keygen -Y sign, see PROTOCOL.sshsig in OpenSSH
+const MAGIC: &[u8] = b"SSHSIG";
+const VERSION: u32 = 1;
+const BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+const END: &str = "-----END SSH SIGNATURE-----";
+// ssh-keygen wraps the armored blob at 70 characters too
+const LINE_LEN: usize = 70;
+
+pub static HASH_ALGORITHMS: &[&str] = &["sha512", "sha256"];
+pub static DEFAULT_HASH_ALGORITHM: &str = "sha512";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshSig {
+	pub public_key: PublicKey,
+	// what the signature is for, e.g. "file" or "git", so it can't be replayed elsewhere
+	pub namespace: String,
+	pub hash_algorithm: String,
+	pub signature: Signature,
+}
+
+impl SshSig {
+
+	// signs the `digest` of a message, from the digest function, with a key of the store
+	pub fn sign(store: &mut KeyStore, key_id: &KeyId, namespace: &str, hash_algorithm: &str, digest: &[u8]) -> Result<SshSig, &'static str> {
+		let key = store.get_public_key(key_id).ok_or("Key not found")?;
+		let data = signed_data(namespace, hash_algorithm, digest)?;
+		Ok(SshSig {
+			public_key: key.pubkey.key,
+			namespace: namespace.to_string(),
+			hash_algorithm: hash_algorithm.to_string(),
+			signature: store.ssh_signature(key_id, &data)?,
+		})
+	}
+
+	// checks the signature is over `digest` for `namespace`. It says nothing about who
+	// signed, which is for allowed_signers to tell.
+	pub fn verify(&self, namespace: &str, digest: &[u8]) -> Result<(), &'static str> {
+		if self.namespace != namespace {
+			return Err("Signature was made for another namespace");
+		}
+		if self.signature.algorithm() == RSA_TYPE {
+			return Err("RSA signatures with SHA-1 aren't accepted");
+		}
+		let data = signed_data(&self.namespace, &self.hash_algorithm, digest)?;
+		match self.public_key.verify(&data, &self.signature)? {
+			true => Ok(()),
+			false => Err("Signature doesn't match the message"),
+		}
+	}
+
+	pub fn to_blob(&self) -> Vec<u8> {
+		let mut writer = Writer::new();
+		writer.write_raw(MAGIC);
+		writer.write_u32(VERSION);
+		writer.write_string(&self.public_key.to_blob());
+		writer.write_string(self.namespace.as_bytes());
+		writer.write_string(b"");
+		writer.write_string(self.hash_algorithm.as_bytes());
+		writer.write_string(&self.signature.to_blob());
+		writer.into_vec()
+	}
+
+	pub fn from_blob(blob: &[u8]) -> Result<SshSig, &'static str> {
+		if !blob.starts_with(MAGIC) {
+			return Err("Not an SSH signature");
+		}
+		let mut reader = Reader::new(&blob[MAGIC.len()..]);
+		if reader.read_u32()? != VERSION {
+			return Err("Unsupported SSH signature version");
+		}
+		let public_key = PublicKey::from_blob(reader.read_string()?)?;
+		let namespace = String::from_utf8(reader.read_string()?.to_vec()).map_err(|_| "Invalid namespace")?;
+		reader.read_string()?;
+		let hash_algorithm = String::from_utf8(reader.read_string()?.to_vec()).map_err(|_| "Invalid hash algorithm")?;
+		message_digest(&hash_algorithm)?;
+		let signature = Signature::from_blob(reader.read_string()?)?;
+		reader.finish()?;
+		Ok(SshSig {
+			public_key: public_key,
+			namespace: namespace,
+			hash_algorithm: hash_algorithm,
+			signature: signature,
+		})
+	}
+
+	// the -----BEGIN SSH SIGNATURE----- block of .sig files
+	pub fn to_armored(&self) -> String {
+		let encoded = base64::encode(&self.to_blob());
+		let mut lines = vec![BEGIN.to_string()];
+		lines.extend(encoded.as_bytes().chunks(LINE_LEN).map(|chunk| String::from_utf8_lossy(chunk).into_owned()));
+		lines.push(END.to_string());
+		lines.join("\n")
+	}
+
+	pub fn from_armored(armored: &str) -> Result<SshSig, &'static str> {
+		let armored = armored.trim();
+		if !armored.starts_with(BEGIN) || !armored.ends_with(END) || armored.len() < BEGIN.len() + END.len() {
+			return Err("Not an armored SSH signature");
+		}
+		let body: String = armored[BEGIN.len()..armored.len() - END.len()].split_whitespace().collect();
+		let blob = base64::decode(&body).map_err(|_| "Invalid base64 in SSH signature")?;
+		SshSig::from_blob(&blob)
+	}
+}
+
+// the message hashed with `hash_algorithm`, read in chunks as artifacts can be large
+pub fn digest<R: Read>(hash_algorithm: &str, message: &mut R) -> Result<Vec<u8>, String> {
+	let mut hasher = Hasher::new(message_digest(hash_algorithm)?).map_err(|_| "Error hashing message")?;
+	io::copy(message, &mut hasher).map_err(|err| format!("Error reading message: {}", err))?;
+	Ok(hasher.finish().map_err(|_| "Error hashing message")?.to_vec())
+}
+
+fn message_digest(hash_algorithm: &str) -> Result<MessageDigest, &'static str> {
+	match hash_algorithm {
+		"sha256" => Ok(MessageDigest::sha256()),
+		"sha512" => Ok(MessageDigest::sha512()),
+		_ => Err("Unsupported hash algorithm"),
+	}
+}
+
+// what the key actually signs
+fn signed_data(namespace: &str, hash_algorithm: &str, digest: &[u8]) -> Result<Vec<u8>, &'static str> {
+	if namespace.is_empty() {
+		return Err("The namespace can't be empty");
+	}
+	message_digest(hash_algorithm)?;
+	let mut writer = Writer::new();
+	writer.write_raw(MAGIC);
+	writer.write_string(namespace.as_bytes());
+	writer.write_string(b"");
+	writer.write_string(hash_algorithm.as_bytes());
+	writer.write_string(digest);
+	Ok(writer.into_vec())
+}
+
+// a line of an allowed_signers file, see ssh-keygen(1)
+#[derive(Debug, Clone)]
+pub struct AllowedSigner {
+	// comma separated patterns, "!" negating one
+	pub principals: String,
+	// the key signs certificates rather than messages, which we don't verify
+	pub cert_authority: bool,
+	// patterns of the namespaces the key may sign for, any when None
+	pub namespaces: Option<String>,
+	// local time in the file unless it ends in Z, as ssh-keygen reads it
+	pub valid_after: Option<u64>,
+	pub valid_before: Option<u64>,
+	pub key: PublicKey,
+}
+
+impl AllowedSigner {
+
+	// whether the line lets `key` sign for `principal` in `namespace` at `time`
+	pub fn allows(&self, principal: &str, namespace: &str, key: &PublicKey, time: u64) -> bool {
+		!self.cert_authority
+			&& self.key == *key
+			&& match_pattern_list(principal, &self.principals)
+			&& self.namespaces.as_ref().is_none_or(|namespaces| match_pattern_list(namespace, namespaces))
+			&& self.valid_after.is_none_or(|after| time >= after)
+			&& self.valid_before.is_none_or(|before| time < before)
+	}
+
+	pub fn parse(line: &str) -> Result<AllowedSigner, String> {
+		let (principals, rest) = split_options(line.trim());
+		let (options, key) = match cert::parse_public_key(rest) {
+			Ok(_) => ("", rest),
+			Err(_) => split_options(rest),
+		};
+		let (blob, _) = cert::parse_public_key(key)?;
+		let mut signer = AllowedSigner {
+			principals: unquote(principals),
+			cert_authority: false,
+			namespaces: None,
+			valid_after: None,
+			valid_before: None,
+			key: PublicKey::from_blob(&blob)?,
+		};
+		if signer.principals.is_empty() {
+			return Err(String::from("Missing principals"));
+		}
+		for option in split_list(options) {
+			let (name, value) = match option.find('=') {
+				Some(position) => (&option[..position], Some(unquote(&option[position + 1..]))),
+				None => (option.as_str(), None),
+			};
+			match (name.to_lowercase().as_str(), value) {
+				("cert-authority", None) => signer.cert_authority = true,
+				("namespaces", Some(value)) => signer.namespaces = Some(value),
+				("valid-after", Some(value)) => signer.valid_after = Some(parse_time(&value)?),
+				("valid-before", Some(value)) => signer.valid_before = Some(parse_time(&value)?),
+				_ => return Err(format!("Unsupported option \"{}\"", option)),
+			}
+		}
+		Ok(signer)
+	}
+}
+
+// the signers of an allowed_signers file. Lines that don't parse are skipped, they
+// could only ever allow something.
+pub fn parse_allowed_signers(name: &str, contents: &str) -> Vec<AllowedSigner> {
+	contents.lines()
+		.enumerate()
+		.filter(|&(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+		.filter_map(|(number, line)| match AllowedSigner::parse(line) {
+			Ok(signer) => Some(signer),
+			Err(err) => {
+				warn!("{}:{}: skipping allowed signer: {}", name, number + 1, err);
+				None
+			}
+		})
+		.collect()
+}
+
+// YYYYMMDD[HHMM[SS]], UTC when it ends in Z and local time otherwise
+fn parse_time(time: &str) -> Result<u64, String> {
+	let invalid = || format!("Invalid time \"{}\"", time);
+	let (digits, utc) = match time.strip_suffix(['Z', 'z']) {
+		Some(digits) => (digits, true),
+		None => (time, false),
+	};
+	if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+		return Err(invalid());
+	}
+	let utc_time = cert::parse_time(digits, 0)?;
+	if utc {
+		return Ok(utc_time);
+	}
+
+	// the same date and time again, read in the local time zone
+	let mut tm: libc::tm = unsafe { mem::zeroed() };
+	let seconds = utc_time as libc::time_t;
+	if unsafe { libc::gmtime_r(&seconds, &mut tm) }.is_null() {
+		return Err(invalid());
+	}
+	tm.tm_isdst = -1;
+	match unsafe { libc::mktime(&mut tm) } {
+		-1 => Err(invalid()),
+		local => Ok(local.max(0) as u64),
+	}
+}
+
+fn unquote(value: &str) -> String {
+	let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') { &value[1..value.len() - 1] } else { value };
+	value.replace("\\\"", "\"")
+}
+
+// options split at the commas outside quotes
+fn split_list(options: &str) -> Vec<String> {
+	let mut items = vec![];
+	let mut rest = options;
+	while !rest.is_empty() {
+		let mut quoted = false;
+		let end = rest.char_indices()
+			.find(|&(_, c)| {
+				if c == '"' {
+					quoted = !quoted;
+				}
+				c == ',' && !quoted
+			})
+			.map_or(rest.len(), |(index, _)| index);
+		items.push(rest[..end].to_string());
+		rest = if end < rest.len() { &rest[end + 1..] } else { "" };
+	}
+	items
+}
+
+// OpenSSH's pattern lists: matching any pattern but none of the "!" ones
+fn match_pattern_list(value: &str, patterns: &str) -> bool {
+	let mut matched = false;
+	for pattern in patterns.split(',').map(str::trim) {
+		if let Some(negated) = pattern.strip_prefix('!') {
+			if match_pattern(value.as_bytes(), negated.as_bytes()) {
+				return false;
+			}
+		} else if match_pattern(value.as_bytes(), pattern.as_bytes()) {
+			matched = true;
+		}
+	}
+	matched
+}
+
+// "*" for any run of characters, "?" for exactly one
+fn match_pattern(value: &[u8], pattern: &[u8]) -> bool {
+	match pattern.split_first() {
+		None => value.is_empty(),
+		Some((&b'*', rest)) => (0..value.len() + 1).any(|skip| match_pattern(&value[skip..], rest)),
+		Some((&b'?', rest)) => !value.is_empty() && match_pattern(&value[1..], rest),
+		Some((&c, rest)) => value.first() == Some(&c) && match_pattern(&value[1..], rest),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::PathBuf;
+
+	use hex;
+
+	use ed25519::{ED25519_KEY_LEN, ED25519_TYPE};
+	use software::SoftwareBackend;
+
+	// signed by ssh-keygen -Y sign -n file, with the keys below
+	const MESSAGE: &[u8] = b"sekey test vector\n";
+
+	const ED25519_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIIb4aoZ2MEYYQUTOFkAyLqPa+7Ml/muhTl8x5azkeYdQ sekey test";
+	const ED25519_SEED: &str = "3eedb01fb57cfceff78241e838f7519eab81312a8f285d3fdecb7d91a442f5f3";
+	// Ed25519 signatures are deterministic, so signing with the same key has to give this back
+	const ED25519_SIG: &str = "-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAghvhqhnYwRhhBRM4WQDIuo9r7sy
+X+a6FOXzHlrOR5h1AAAAAEZmlsZQAAAAAAAAAGc2hhNTEyAAAAUwAAAAtzc2gtZWQyNTUx
+OQAAAEBDaVBR6b6L4+Rlhrpry6NhQDVMpv6ic4GwoL02DLCZQR8PvMyd89Dvp1VTaVbLI0
+15zZtZeB/SpipNFTooHB8P
+-----END SSH SIGNATURE-----";
+
+	const P256_KEY: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBL6ULOqxSGsMaXeBEVlQKpRvhamzLiRGGH8yTSQWN72NRpVFFRLOZqdmiU6T6EXVUYAe7VmHSqSrN+e4dAaRl1M= sekey test";
+	const P256_SIG: &str = "-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAAGgAAAATZWNkc2Etc2hhMi1uaXN0cDI1NgAAAAhuaXN0cDI1NgAAAE
+EEvpQs6rFIawxpd4ERWVAqlG+FqbMuJEYYfzJNJBY3vY1GlUUVEs5mp2aJTpPoRdVRgB7t
+WYdKpKs357h0BpGXUwAAAARmaWxlAAAAAAAAAAZzaGE1MTIAAABlAAAAE2VjZHNhLXNoYT
+ItbmlzdHAyNTYAAABKAAAAIQCQCNgrRoiSVZlbLII4YV5LDR8il7S/5JsJvJiOLsP8wwAA
+ACEAn9ckZ14ZpA0CCel5Na2kteXIxPTaWl5g25dsVjWEuXQ=
+-----END SSH SIGNATURE-----";
+
+	fn public_key(line: &str) -> PublicKey {
+		PublicKey::from_blob(&cert::parse_public_key(line).unwrap().0).unwrap()
+	}
+
+	fn message_digest() -> Vec<u8> {
+		digest(DEFAULT_HASH_ALGORITHM, &mut &MESSAGE[..]).unwrap()
+	}
+
+	#[test]
+	fn verifies_ssh_keygen_signatures() {
+		for &(key, armored) in &[(ED25519_KEY, ED25519_SIG), (P256_KEY, P256_SIG)] {
+			let signature = SshSig::from_armored(armored).unwrap();
+			assert_eq!(signature.public_key, public_key(key));
+			assert_eq!(signature.namespace, "file");
+			assert_eq!(signature.hash_algorithm, "sha512");
+			assert_eq!(signature.verify("file", &message_digest()), Ok(()));
+			assert_eq!(signature.to_armored(), armored);
+		}
+	}
+
+	#[test]
+	fn rejects_other_messages_and_namespaces() {
+		let signature = SshSig::from_armored(P256_SIG).unwrap();
+		assert!(signature.verify("git", &message_digest()).is_err());
+		let other = digest(DEFAULT_HASH_ALGORITHM, &mut &b"sekey test vector"[..]).unwrap();
+		assert!(signature.verify("file", &other).is_err());
+	}
+
+	#[test]
+	fn signs_like_ssh_keygen() {
+		let seed = hex::decode(ED25519_SEED).unwrap();
+		let blob = public_key(ED25519_KEY).to_blob();
+		let public = &blob[blob.len() - ED25519_KEY_LEN..];
+		// what ssh-add sends: the type, the public key, the seed followed by the public key
+		let mut identity = Writer::new();
+		identity.write_string(ED25519_TYPE.as_bytes());
+		identity.write_string(public);
+		identity.write_string(&[&seed[..], public].concat());
+		identity.write_string(b"sekey test");
+
+		// keys from ssh-add stay in memory, the folder is never read from
+		let backend = SoftwareBackend::new(PathBuf::from("/nonexistent"));
+		let mut store = KeyStore::new(vec![Box::new(backend)]);
+		store.add_identity(&identity.into_vec()).unwrap();
+		let key = store.find_by_key(&public_key(ED25519_KEY)).unwrap();
+
+		let signature = SshSig::sign(&mut store, &key.pubkey.id, "file", DEFAULT_HASH_ALGORITHM, &message_digest()).unwrap();
+		assert_eq!(signature.to_armored(), ED25519_SIG);
+	}
+
+	#[test]
+	fn parses_allowed_signers() {
+		let line = format!("*@example.com,!mallory@example.com namespaces=\"git,file\",valid-after=\"20240101Z\",valid-before=\"20250630123015Z\" {}", P256_KEY);
+		let signer = AllowedSigner::parse(&line).unwrap();
+		assert_eq!(signer.principals, "*@example.com,!mallory@example.com");
+		assert_eq!(signer.namespaces, Some(String::from("git,file")));
+		assert_eq!(signer.valid_after, Some(1704067200));
+		assert_eq!(signer.valid_before, Some(1751286615));
+		assert!(!signer.cert_authority);
+
+		let key = public_key(P256_KEY);
+		let time = 1704067200;
+		assert!(signer.allows("alice@example.com", "file", &key, time));
+		assert!(signer.allows("alice@example.com", "git", &key, time));
+		assert!(!signer.allows("mallory@example.com", "file", &key, time));
+		assert!(!signer.allows("alice@example.org", "file", &key, time));
+		assert!(!signer.allows("alice@example.com", "ssh", &key, time));
+		assert!(!signer.allows("alice@example.com", "file", &public_key(ED25519_KEY), time));
+		assert!(!signer.allows("alice@example.com", "file", &key, time - 1));
+		assert!(signer.allows("alice@example.com", "file", &key, 1751286614));
+		assert!(!signer.allows("alice@example.com", "file", &key, 1751286615));
+	}
+
+	#[test]
+	fn reads_times_without_z_as_local_time() {
+		let time = parse_time("20250630123015").unwrap() as libc::time_t;
+		let mut tm: libc::tm = unsafe { mem::zeroed() };
+		assert!(!unsafe { libc::localtime_r(&time, &mut tm) }.is_null());
+		assert_eq!((tm.tm_year, tm.tm_mon, tm.tm_mday), (125, 5, 30));
+		assert_eq!((tm.tm_hour, tm.tm_min, tm.tm_sec), (12, 30, 15));
+
+		assert_eq!(parse_time("20250630123015Z"), Ok(1751286615));
+		assert_eq!(parse_time("20250630123015z"), Ok(1751286615));
+		assert!(parse_time("20250631").is_err());
+		assert!(parse_time("Z").is_err());
+	}
+
+	#[test]
+	fn parses_signers_without_options() {
+		let signer = AllowedSigner::parse(&format!("alice@example.com {}", ED25519_KEY)).unwrap();
+		assert_eq!(signer.namespaces, None);
+		assert!(signer.allows("alice@example.com", "anything", &public_key(ED25519_KEY), 0));
+
+		let authority = AllowedSigner::parse(&format!("* cert-authority {}", ED25519_KEY)).unwrap();
+		assert!(authority.cert_authority);
+		assert!(!authority.allows("alice@example.com", "file", &public_key(ED25519_KEY), 0));
+	}
+
+	#[test]
+	fn rejects_invalid_allowed_signers() {
+		assert!(AllowedSigner::parse(ED25519_KEY).is_err());
+		assert!(AllowedSigner::parse(&format!("alice@example.com valid-after=yesterday {}", ED25519_KEY)).is_err());
+		assert!(AllowedSigner::parse(&format!("alice@example.com namespaces {}", ED25519_KEY)).is_err());
+		assert!(AllowedSigner::parse(&format!("alice@example.com no-touch-required {}", ED25519_KEY)).is_err());
+		assert!(AllowedSigner::parse("alice@example.com ssh-ed25519 AAAA").is_err());
+	}
+
+	#[test]
+	fn matches_pattern_lists() {
+		assert!(match_pattern_list("git", "git,file"));
+		assert!(match_pattern_list("file", "git, file"));
+		assert!(!match_pattern_list("ssh", "git,file"));
+		assert!(match_pattern_list("alice@example.com", "*@example.com"));
+		assert!(match_pattern_list("bob", "b?b"));
+		assert!(!match_pattern_list("boob", "b?b"));
+		assert!(!match_pattern_list("mallory@example.com", "*@example.com,!mallory@*"));
+		// a negated pattern alone never allows anything
+		assert!(!match_pattern_list("alice", "!mallory"));
+		assert!(match_pattern_list("", "*"));
+	}
+}
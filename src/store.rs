@@ -0,0 +1,617 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use cert::{self, CertStore, Certificate, StoredCert};
+use config::Config;
+use ed25519::ED25519_TYPE;
+use agent::SSH_AGENT_RSA_SHA2_512;
+#[cfg(target_os = "macos")]
+use ecdsa::Curve;
+use home;
+use key::{KeyId, PublicKey, Signature};
+use rsa::RSA_TYPE;
+use software::SoftwareBackend;
+use wire::{Reader, Writer};
+#[cfg(target_os = "macos")]
+use Keychain;
+
+pub static BACKENDS: &[&str] = &["keychain", "software"];
+// keys no backend holds that are remembered between refreshes, the set starts over
+// when it gets bigger as clients may offer any number of keys
+const MAX_UNKNOWN_KEYS: usize = 1024;
+
+pub static KEY_TYPES: &[&str] = &["ecdsa-sha2-nistp256", "ecdsa-sha2-nistp384", "ecdsa-sha2-nistp521", "ssh-ed25519", "ssh-rsa"];
+
+#[derive(Debug, Clone)]
+pub struct PubKey {
+	pub label: String,
+	pub id: KeyId,
+	pub key: PublicKey,
+}
+
+// where a key came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySource {
+	SecureEnclave,
+	File(PathBuf),
+	SshAdd,
+}
+
+impl KeySource {
+	pub fn describe(&self) -> String {
+		match *self {
+			KeySource::SecureEnclave => String::from("secure-enclave"),
+			KeySource::File(ref path) => format!("file:{}", path.display()),
+			KeySource::SshAdd => String::from("ssh-add"),
+		}
+	}
+}
+
+pub fn is_available(backend: &str) -> bool {
+	match backend {
+		"keychain" => cfg!(target_os = "macos"),
+		"software" => true,
+		_ => false,
+	}
+}
+
+pub fn default_backends() -> Vec<String> {
+	BACKENDS.iter()
+		.filter(|backend| is_available(backend))
+		.map(|backend| backend.to_string())
+		.collect()
+}
+
+// somewhere keys live, keys are addressed by their ID
+pub trait Backend: Send {
+	fn name(&self) -> &'static str;
+	fn key_types(&self) -> &'static [&'static str];
+	fn public_keys(&self) -> Vec<PubKey>;
+	fn source(&self, key_id: &KeyId) -> KeySource;
+
+	// signs `data` with the key: ECDSA with the hash of the key's curve, Ed25519 as it
+	// is, and RSA with the hash `algorithm` names, see PublicKey::signature_algorithm
+	fn sign(&self, key_id: &KeyId, data: &[u8], algorithm: &str) -> Result<Signature, &'static str>;
+
+	fn generate(&mut self, label: &str, key_type: &str) -> Result<(), &'static str>;
+
+	// stores a private key file under `label`, in whatever formats the backend reads
+	fn import(&mut self, _label: &str, _contents: &[u8]) -> Result<(), &'static str> {
+		Err("Backend can't import keys")
+	}
+	fn delete(&mut self, key_id: &KeyId) -> Result<(), &'static str>;
+
+	// keys handed over by ssh-add, `key` is the agent protocol encoding
+	fn add_identity(&mut self, _key: &[u8]) -> Result<(), &'static str> {
+		Err("Backend doesn't accept keys from ssh-add")
+	}
+
+	fn remove_identity(&mut self, _key_id: &KeyId) -> Result<(), &'static str> {
+		Err("Backend doesn't accept keys from ssh-add")
+	}
+
+	fn remove_all_identities(&mut self) {}
+
+	// picks up keys created or removed behind our back
+	fn refresh(&mut self) {}
+
+	// forgets every key held in memory, returns how many were dropped
+	fn drop_keys(&mut self) -> usize {
+		0
+	}
+}
+
+#[cfg(target_os = "macos")]
+impl Backend for Keychain {
+
+	fn name(&self) -> &'static str {
+		"keychain"
+	}
+
+	// the Secure Enclave only does P-256
+	fn key_types(&self) -> &'static [&'static str] {
+		&KEY_TYPES[..1]
+	}
+
+	fn public_keys(&self) -> Vec<PubKey> {
+		self.get_public_keys()
+	}
+
+	fn source(&self, _key_id: &KeyId) -> KeySource {
+		KeySource::SecureEnclave
+	}
+
+	fn sign(&self, key_id: &KeyId, data: &[u8], _algorithm: &str) -> Result<Signature, &'static str> {
+		let signed = self.sign_data(data.to_vec(), key_id.as_bytes().to_vec())?;
+		Signature::from_ecdsa_der(Curve::Nistp256, signed)
+	}
+
+	fn generate(&mut self, label: &str, _key_type: &str) -> Result<(), &'static str> {
+		self.generate_keypair(label.to_string())
+	}
+
+	fn delete(&mut self, key_id: &KeyId) -> Result<(), &'static str> {
+		self.delete_keypair(key_id.as_bytes().to_vec())
+	}
+
+	fn refresh(&mut self) {
+		self.forget_refs();
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredKey {
+	pub backend: &'static str,
+	pub source: KeySource,
+	pub pubkey: PubKey,
+}
+
+impl StoredKey {
+	pub fn key_type(&self) -> &'static str {
+		self.pubkey.key.key_type()
+	}
+
+	// the public key as it travels in the agent protocol
+	pub fn blob(&self) -> Vec<u8> {
+		self.pubkey.key.to_blob()
+	}
+}
+
+// keys of every backend, indexed by ID and by public key. Built on the first lookup
+// after being invalidated, so the backends are only asked again on a miss.
+struct IdentityCache {
+	keys: Vec<StoredKey>,
+	by_id: HashMap<KeyId, usize>,
+	by_key: HashMap<PublicKey, usize>,
+	// keys clients asked for that weren't there even after asking the backends again
+	unknown: HashSet<PublicKey>,
+}
+
+impl IdentityCache {
+
+	fn build(backends: &[Box<dyn Backend>]) -> Self {
+		let mut cache = IdentityCache { keys: vec![], by_id: HashMap::new(), by_key: HashMap::new(), unknown: HashSet::new() };
+		for backend in backends {
+			for pubkey in backend.public_keys() {
+				let key = StoredKey {
+					backend: backend.name(),
+					source: backend.source(&pubkey.id),
+					pubkey: pubkey,
+				};
+				// the first backend wins when the same key shows up twice, like the listing order
+				if cache.by_id.contains_key(&key.pubkey.id) {
+					continue;
+				}
+				cache.by_id.insert(key.pubkey.id.clone(), cache.keys.len());
+				cache.by_key.insert(key.pubkey.key.clone(), cache.keys.len());
+				cache.keys.push(key);
+			}
+		}
+		cache
+	}
+}
+
+pub struct KeyStore {
+	backends: Vec<Box<dyn Backend>>,
+	cache: Option<IdentityCache>,
+	certs: CertStore,
+}
+
+impl KeyStore {
+
+	pub fn new(backends: Vec<Box<dyn Backend>>) -> Self {
+		Self { backends: backends, cache: None, certs: CertStore::empty() }
+	}
+
+	pub fn from_config(config: &Config) -> Result<KeyStore, String> {
+		let mut backends: Vec<Box<dyn Backend>> = vec![];
+		for name in &config.backends {
+			backends.push(KeyStore::backend(config, name)?);
+		}
+		let mut store = KeyStore::new(backends);
+		store.certs = CertStore::new(home::sekey_home()?.join(CertStore::CERTS_FOLDER));
+		Ok(store)
+	}
+
+	#[cfg(target_os = "macos")]
+	fn keychain(config: &Config) -> Result<Box<dyn Backend>, String> {
+		Ok(Box::new(Keychain::new(&config.keychain.private_tag, &config.keychain.public_tag, &config.prompt)))
+	}
+
+	#[cfg(not(target_os = "macos"))]
+	fn keychain(_config: &Config) -> Result<Box<dyn Backend>, String> {
+		Err(String::from("The keychain backend is only available on macOS"))
+	}
+
+	fn backend(config: &Config, name: &str) -> Result<Box<dyn Backend>, String> {
+		match name {
+			"keychain" => KeyStore::keychain(config),
+			"software" => {
+				let dir = home::sekey_home()?.join(SoftwareBackend::KEYS_FOLDER);
+				Ok(Box::new(SoftwareBackend::new(dir)))
+			}
+			_ => Err(format!("Unknown backend {}", name)),
+		}
+	}
+
+	fn cache(&mut self) -> &IdentityCache {
+		if self.cache.is_none() {
+			self.cache = Some(IdentityCache::build(&self.backends));
+		}
+		self.cache.as_ref().unwrap()
+	}
+
+	// keys may come and go behind our back (another sekey generating one), so a key
+	// that isn't cached gets one more look at the backends
+	fn lookup<F: Fn(&IdentityCache) -> Option<usize>>(&mut self, find: F) -> Option<StoredKey> {
+		if let Some(index) = find(self.cache()) {
+			return Some(self.cache().keys[index].clone());
+		}
+		self.invalidate();
+		let cache = self.cache();
+		find(cache).map(|index| cache.keys[index].clone())
+	}
+
+	pub fn invalidate(&mut self) {
+		self.cache = None;
+	}
+
+	pub fn public_keys(&mut self) -> Vec<StoredKey> {
+		self.cache().keys.clone()
+	}
+
+	pub fn get_public_key(&mut self, key_id: &KeyId) -> Option<StoredKey> {
+		self.lookup(|cache| cache.by_id.get(key_id).cloned())
+	}
+
+	// clients offer every key they have, so the ones we don't hold are remembered until
+	// the next refresh rather than sending each of their requests through the backends
+	pub fn find_by_key(&mut self, key: &PublicKey) -> Option<StoredKey> {
+		if self.cache().unknown.contains(key) {
+			return None;
+		}
+		let found = self.lookup(|cache| cache.by_key.get(key).cloned());
+		if found.is_none() {
+			if let Some(ref mut cache) = self.cache {
+				if cache.unknown.len() >= MAX_UNKNOWN_KEYS {
+					cache.unknown.clear();
+				}
+				cache.unknown.insert(key.clone());
+			}
+		}
+		found
+	}
+
+	// the key the user means by `selector`: its ID, its label or the start of a single
+	// one, its SHA256 fingerprint or the start of it, or its MD5 fingerprint. Exact
+	// matches win over prefixes, so a label that starts another one still selects its key
+	pub fn select(&mut self, selector: &str) -> Result<StoredKey, String> {
+		if selector.is_empty() {
+			return Err("No key selected".to_string());
+		}
+		let key_id = KeyId::from_hex(selector).ok();
+		let keys = self.public_keys();
+		let exact: Vec<&StoredKey> = keys.iter()
+			.filter(|key| {
+				key_id.as_ref() == Some(&key.pubkey.id)
+					|| key.pubkey.label == selector
+					|| key.pubkey.key.fingerprint() == selector
+					|| key.pubkey.key.md5_fingerprint().eq_ignore_ascii_case(selector)
+			})
+			.collect();
+		let matching = if !exact.is_empty() {
+			exact
+		} else {
+			keys.iter()
+				.filter(|key| {
+					key.pubkey.label.starts_with(selector)
+						|| (selector.starts_with("SHA256:") && key.pubkey.key.fingerprint().starts_with(selector))
+				})
+				.collect()
+		};
+		match matching.len() {
+			0 => Err(format!("No key matches \"{}\"", selector)),
+			1 => Ok(matching[0].clone()),
+			_ => {
+				let keys: Vec<String> = matching.iter()
+					.map(|key| format!("{} ({})", key.pubkey.label, key.pubkey.key.fingerprint()))
+					.collect();
+				Err(format!("\"{}\" matches several keys: {}", selector, keys.join(", ")))
+			}
+		}
+	}
+
+	// certificates valid at `time` for keys we hold, along with their key
+	pub fn certificates(&mut self, time: u64) -> Vec<(StoredCert, StoredKey)> {
+		let certs: Vec<StoredCert> = self.certs.valid(time).into_iter().cloned().collect();
+		let cache = self.cache();
+		certs.into_iter()
+			.filter_map(|cert| {
+				let index = *cache.by_key.get(&cert.certificate.public_key().ok()?)?;
+				Some((cert, cache.keys[index].clone()))
+			})
+			.collect()
+	}
+
+	// every certificate, expired or not
+	pub fn stored_certificates(&self) -> &[StoredCert] {
+		self.certs.all()
+	}
+
+	// the key a certificate blob was issued for, as long as the certificate is valid
+	pub fn certificate_key(&mut self, blob: &[u8], time: u64) -> Option<StoredKey> {
+		let subject = self.certs.find(blob, time)?.certificate.public_key().ok()?;
+		self.find_by_key(&subject)
+	}
+
+	fn backend_of(&mut self, key_id: &KeyId) -> Option<&mut Box<dyn Backend>> {
+		let name = self.get_public_key(key_id)?.backend;
+		self.backends.iter_mut().find(|backend| backend.name() == name)
+	}
+
+	pub fn sign(&mut self, key_id: &KeyId, data: &[u8], algorithm: &str) -> Result<Signature, &'static str> {
+		let signature = match self.backend_of(key_id) {
+			Some(backend) => backend.sign(key_id, data, algorithm)?,
+			None => return Err("Key not found"),
+		};
+		// nothing goes out that wouldn't read back as the same signature
+		if Signature::from_blob(&signature.to_blob()).ok().as_ref() != Some(&signature) {
+			return Err("Backend returned a malformed signature");
+		}
+		Ok(signature)
+	}
+
+	// signature as certificates and KRLs carry them, where RSA signs with SHA-512 like
+	// ssh-keygen does
+	pub fn ssh_signature(&mut self, key_id: &KeyId, data: &[u8]) -> Result<Signature, &'static str> {
+		let key = self.get_public_key(key_id).ok_or("Key not found")?;
+		let algorithm = key.pubkey.key.signature_algorithm(SSH_AGENT_RSA_SHA2_512);
+		self.sign(key_id, data, algorithm)
+	}
+
+	pub fn delete(&mut self, key_id: &KeyId) -> Result<(), &'static str> {
+		let result = match self.backend_of(key_id) {
+			Some(backend) => backend.delete(key_id),
+			None => Err("Key not found"),
+		};
+		self.invalidate();
+		result
+	}
+
+	// with no backend given, the first enabled one that supports the key type is used
+	pub fn generate(&mut self, backend: Option<&str>, label: &str, key_type: &str) -> Result<&'static str, &'static str> {
+		let backend = self.backends.iter_mut()
+			.filter(|candidate| backend.is_none_or(|name| candidate.name() == name))
+			.find(|candidate| candidate.key_types().contains(&key_type))
+			.ok_or("No enabled backend can generate this key type")?;
+		let result = backend.generate(label, key_type).map(|_| backend.name());
+		self.invalidate();
+		result
+	}
+
+	pub fn import(&mut self, backend: Option<&str>, label: &str, contents: &[u8]) -> Result<&'static str, &'static str> {
+		self.invalidate();
+		let mut result = Err("No enabled backend can import keys");
+		for candidate in self.backends.iter_mut().filter(|candidate| backend.is_none_or(|name| candidate.name() == name)) {
+			result = candidate.import(label, contents).map(|_| candidate.name());
+			if result.is_ok() {
+				break;
+			}
+		}
+		result
+	}
+
+	pub fn add_identity(&mut self, key: &[u8]) -> Result<(), &'static str> {
+		if cert::is_certificate(key) {
+			return self.add_certificate(key);
+		}
+		self.invalidate();
+		let mut result = Err("No enabled backend accepts keys from ssh-add");
+		for backend in self.backends.iter_mut() {
+			result = backend.add_identity(key);
+			if result.is_ok() {
+				break;
+			}
+		}
+		result
+	}
+
+	// ssh-add sends the certificate in place of the public key, followed by the private
+	// key. The key goes to the backends like any other, the certificate stays with us.
+	fn add_certificate(&mut self, key: &[u8]) -> Result<(), &'static str> {
+		let mut reader = Reader::new(key);
+		reader.read_string().map_err(|_| "Invalid key from ssh-add")?;
+		let blob = reader.read_string().map_err(|_| "Invalid key from ssh-add")?;
+		let certificate = Certificate::parse(blob)?;
+
+		// what follows only has what the certificate lacks: d onwards for RSA, the
+		// public and private key for Ed25519, the scalar for ECDSA
+		let subject = certificate.public_key()?;
+		let mut plain = Writer::new();
+		match subject {
+			PublicKey::Rsa { ref e, ref n } => {
+				plain.write_string(RSA_TYPE.as_bytes());
+				plain.write_mpint(n);
+				plain.write_mpint(e);
+			}
+			PublicKey::Ed25519(_) => plain.write_string(ED25519_TYPE.as_bytes()),
+			ref ecdsa => plain.write_raw(&ecdsa.to_blob()),
+		}
+		plain.write_raw(&key[key.len() - reader.remaining()..]);
+		let plain = plain.into_vec();
+		// caught here rather than when the certificate fails to sign
+		if SoftwareBackend::identity_key(&plain)? != subject {
+			return Err("Private key doesn't match the certificate");
+		}
+		self.add_identity(&plain)?;
+		self.certs.add(certificate, blob.to_vec(), String::new());
+		Ok(())
+	}
+
+	pub fn remove_certificate(&mut self, blob: &[u8]) -> Result<(), &'static str> {
+		self.certs.remove(blob)
+	}
+
+	pub fn remove_identity(&mut self, key_id: &KeyId) -> Result<(), &'static str> {
+		let result = match self.backend_of(key_id) {
+			Some(backend) => backend.remove_identity(key_id),
+			None => Err("Key not found"),
+		};
+		self.invalidate();
+		result
+	}
+
+	pub fn remove_all_identities(&mut self) {
+		self.invalidate();
+		self.certs.remove_all();
+		for backend in self.backends.iter_mut() {
+			backend.remove_all_identities();
+		}
+	}
+
+	pub fn backend_names(&self) -> Vec<&'static str> {
+		self.backends.iter().map(|backend| backend.name()).collect()
+	}
+
+	pub fn refresh(&mut self) {
+		self.invalidate();
+		self.certs.refresh();
+		for backend in self.backends.iter_mut() {
+			backend.refresh();
+		}
+	}
+
+	pub fn drop_keys(&mut self, backend: &str) -> Result<usize, &'static str> {
+		self.invalidate();
+		self.backends.iter_mut()
+			.find(|candidate| candidate.name() == backend)
+			.map(|backend| backend.drop_keys())
+			.ok_or("Backend not enabled")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use ed25519::Ed25519;
+	use hex;
+
+	// a backend holding one key, counting how often its keys are listed
+	struct CountingBackend {
+		key: PubKey,
+		listed: Arc<AtomicUsize>,
+	}
+
+	impl Backend for CountingBackend {
+		fn name(&self) -> &'static str {
+			"counting"
+		}
+
+		fn key_types(&self) -> &'static [&'static str] {
+			&["ssh-ed25519"]
+		}
+
+		fn public_keys(&self) -> Vec<PubKey> {
+			self.listed.fetch_add(1, Ordering::SeqCst);
+			vec![self.key.clone()]
+		}
+
+		fn source(&self, _key_id: &KeyId) -> KeySource {
+			KeySource::SshAdd
+		}
+
+		fn sign(&self, _key_id: &KeyId, _data: &[u8], _algorithm: &str) -> Result<Signature, &'static str> {
+			Err("Not signing")
+		}
+
+		fn generate(&mut self, _label: &str, _key_type: &str) -> Result<(), &'static str> {
+			Err("Not generating")
+		}
+
+		fn delete(&mut self, _key_id: &KeyId) -> Result<(), &'static str> {
+			Err("Not deleting")
+		}
+	}
+
+	fn ed25519_key(byte: u8) -> PublicKey {
+		PublicKey::from_blob(&Ed25519::write(&[byte; 32])).unwrap()
+	}
+
+	// an Ed25519 key and a certificate for it, made with ssh-keygen -s
+	const SUBJECT_SEED: &str = "3eedb01fb57cfceff78241e838f7519eab81312a8f285d3fdecb7d91a442f5f3";
+	const SUBJECT_KEY: &str = "86f86a86763046184144ce1640322ea3dafbb325fe6ba14e5f31e5ace4798750";
+	const CERTIFICATE: &str = concat!(
+		"ssh-ed25519-cert-v01@openssh.com AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIMLIc8iWfMjIjORStGJaV7BjZklmDuFtUrl5",
+		"kEe/Wf7AAAAAIIb4aoZ2MEYYQUTOFkAyLqPa+7Ml/muhTl8x5azkeYdQAAAAAAAAAAEAAAABAAAACnNla2V5LXRlc3QAAAAJAAAABWFsaWNlAAAAAGWSAIAA",
+		"AAAAeGH4AAAAAAAAAACCAAAAFXBlcm1pdC1YMTEtZm9yd2FyZGluZwAAAAAAAAAXcGVybWl0LWFnZW50LWZvcndhcmRpbmcAAAAAAAAAFnBlcm1pdC1wb3J0",
+		"LWZvcndhcmRpbmcAAAAAAAAACnBlcm1pdC1wdHkAAAAAAAAADnBlcm1pdC11c2VyLXJjAAAAAAAAAAAAAABoAAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAI",
+		"bmlzdHAyNTYAAABBBL6ULOqxSGsMaXeBEVlQKpRvhamzLiRGGH8yTSQWN72NRpVFFRLOZqdmiU6T6EXVUYAe7VmHSqSrN+e4dAaRl1MAAABjAAAAE2VjZHNh",
+		"LXNoYTItbmlzdHAyNTYAAABIAAAAIG0MBcQBykYBlNUwPb56mim4srMdUW/RpjloviDxx/VCAAAAICoPpmXzuqLwo/MvjNdgIcFTbAUumahxoQa0sedT0ffL",
+	);
+	// any other Ed25519 key
+	const OTHER_SEED: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+	const OTHER_KEY: &str = "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+
+	// what ssh-add sends for a certificate and an Ed25519 private key
+	fn certificate_identity(seed: &str, public: &str) -> Vec<u8> {
+		let (blob, _) = cert::parse_public_key(CERTIFICATE).unwrap();
+		let public = hex::decode(public).unwrap();
+		let mut identity = Writer::new();
+		identity.write_string(b"ssh-ed25519-cert-v01@openssh.com");
+		identity.write_string(&blob);
+		identity.write_string(&public);
+		identity.write_string(&[hex::decode(seed).unwrap(), public.clone()].concat());
+		identity.write_string(b"sekey test");
+		identity.into_vec()
+	}
+
+	fn software_store() -> KeyStore {
+		// keys from ssh-add stay in memory, the folder is never read from
+		KeyStore::new(vec![Box::new(SoftwareBackend::new(PathBuf::from("/nonexistent")))])
+	}
+
+	#[test]
+	fn adds_certificates_with_their_key() {
+		let mut store = software_store();
+		assert_eq!(store.add_identity(&certificate_identity(SUBJECT_SEED, SUBJECT_KEY)), Ok(()));
+		assert_eq!(store.stored_certificates().len(), 1);
+		assert_eq!(store.certificates(cert::now()).len(), 1);
+	}
+
+	#[test]
+	fn rejects_certificates_for_another_key() {
+		let mut store = software_store();
+		assert!(store.add_identity(&certificate_identity(OTHER_SEED, OTHER_KEY)).is_err());
+		assert!(store.public_keys().is_empty());
+		assert!(store.stored_certificates().is_empty());
+	}
+
+	#[test]
+	fn remembers_unknown_keys_until_refreshed() {
+		let ours = ed25519_key(1);
+		let listed = Arc::new(AtomicUsize::new(0));
+		let backend = CountingBackend {
+			key: PubKey { label: String::from("ours"), id: ours.id(), key: ours.clone() },
+			listed: listed.clone(),
+		};
+		let mut store = KeyStore::new(vec![Box::new(backend)]);
+
+		assert!(store.find_by_key(&ours).is_some());
+		assert_eq!(listed.load(Ordering::SeqCst), 1);
+
+		// the first miss asks the backends again, later ones don't
+		for _ in 0..10 {
+			assert!(store.find_by_key(&ed25519_key(2)).is_none());
+		}
+		assert_eq!(listed.load(Ordering::SeqCst), 2);
+		assert!(store.find_by_key(&ours).is_some());
+		assert_eq!(listed.load(Ordering::SeqCst), 2);
+
+		store.refresh();
+		assert!(store.find_by_key(&ed25519_key(2)).is_none());
+		assert_eq!(listed.load(Ordering::SeqCst), 4);
+	}
+}
@@ -0,0 +1,195 @@
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+// SSH `string`: u32 length followed by the bytes
+pub fn write_string<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+	writer.write_u32::<BigEndian>(data.len() as u32)?;
+	writer.write_all(data)
+}
+
+// SSH `mpint` from unsigned big endian bytes
+pub fn write_mpint<W: Write>(writer: &mut W, value: &[u8]) -> io::Result<()> {
+	write_string(writer, &encode_mpint(value))
+}
+
+// two's complement of an unsigned number, as mpints and DER integers carry it: no
+// leading zeros, except for one when the top bit is set so it doesn't read as negative.
+// Zero has no bytes at all.
+pub fn encode_mpint(value: &[u8]) -> Vec<u8> {
+	let start = value.iter().position(|&byte| byte != 0).unwrap_or(value.len());
+	let value = &value[start..];
+	let mut encoded = Vec::with_capacity(value.len() + 1);
+	if value.first().is_some_and(|&byte| byte & 0x80 != 0) {
+		encoded.push(0);
+	}
+	encoded.extend_from_slice(value);
+	encoded
+}
+
+// the reverse of encode_mpint, refusing negative numbers and leading bytes that
+// encode_mpint wouldn't have written
+pub fn decode_mpint(value: &[u8]) -> Result<&[u8], &'static str> {
+	match value.first() {
+		Some(&byte) if byte & 0x80 != 0 => Err("Negative SSH mpint"),
+		Some(&0) if value.len() == 1 || value[1] & 0x80 == 0 => Err("SSH mpint with a needless leading zero"),
+		Some(&0) => Ok(&value[1..]),
+		_ => Ok(value),
+	}
+}
+
+// longest string a Reader accepts, well above any key, signature or certificate
+pub const MAX_STRING_LEN: usize = 256 * 1024;
+
+// bounds checked reader over a buffer of SSH data: every read returns an error instead
+// of panicking when the data runs out or a length doesn't add up
+pub struct Reader<'a> {
+	data: &'a [u8],
+	position: usize,
+	max_string_len: usize,
+}
+
+impl<'a> Reader<'a> {
+
+	pub fn new(data: &'a [u8]) -> Self {
+		Self { data: data, position: 0, max_string_len: MAX_STRING_LEN }
+	}
+
+	// strings only bounded by the data itself, for files such as KRLs that can be
+	// larger than anything a client sends
+	pub fn unbounded(data: &'a [u8]) -> Self {
+		Self { data: data, position: 0, max_string_len: data.len() }
+	}
+
+	fn take(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+		if len > self.remaining() {
+			return Err("Truncated SSH data");
+		}
+		let taken = &self.data[self.position..self.position + len];
+		self.position += len;
+		Ok(taken)
+	}
+
+	pub fn remaining(&self) -> usize {
+		self.data.len() - self.position
+	}
+
+	pub fn read_u8(&mut self) -> Result<u8, &'static str> {
+		Ok(self.take(1)?[0])
+	}
+
+	pub fn read_u32(&mut self) -> Result<u32, &'static str> {
+		Ok(BigEndian::read_u32(self.take(4)?))
+	}
+
+	pub fn read_u64(&mut self) -> Result<u64, &'static str> {
+		Ok(BigEndian::read_u64(self.take(8)?))
+	}
+
+	pub fn read_string(&mut self) -> Result<&'a [u8], &'static str> {
+		let len = self.read_u32()? as usize;
+		if len > self.max_string_len {
+			return Err("SSH string too long");
+		}
+		self.take(len)
+	}
+
+	// unsigned big endian bytes, see decode_mpint
+	pub fn read_mpint(&mut self) -> Result<&'a [u8], &'static str> {
+		decode_mpint(self.read_string()?)
+	}
+
+	// the string, which has to be `expected`
+	pub fn expect_string(&mut self, expected: &[u8], error: &'static str) -> Result<(), &'static str> {
+		if self.read_string()? != expected {
+			return Err(error);
+		}
+		Ok(())
+	}
+
+	// whatever was read has to be the whole buffer
+	pub fn finish(&self) -> Result<(), &'static str> {
+		if self.remaining() != 0 {
+			return Err("Trailing bytes after SSH data");
+		}
+		Ok(())
+	}
+}
+
+// builds SSH data in memory, where writes can't fail
+#[derive(Default)]
+pub struct Writer {
+	data: Vec<u8>,
+}
+
+impl Writer {
+
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn write_u8(&mut self, value: u8) {
+		self.data.push(value);
+	}
+
+	pub fn write_u32(&mut self, value: u32) {
+		self.data.write_u32::<BigEndian>(value).unwrap();
+	}
+
+	pub fn write_string(&mut self, value: &[u8]) {
+		write_string(&mut self.data, value).unwrap();
+	}
+
+	pub fn write_mpint(&mut self, value: &[u8]) {
+		self.write_string(&encode_mpint(value));
+	}
+
+	// bytes as they are, for data that is already encoded
+	pub fn write_raw(&mut self, value: &[u8]) {
+		self.data.extend_from_slice(value);
+	}
+
+	pub fn into_vec(self) -> Vec<u8> {
+		self.data
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encodes_mpints() {
+		assert!(encode_mpint(&[]).is_empty());
+		assert!(encode_mpint(&[0, 0]).is_empty());
+		assert_eq!(encode_mpint(&[0, 0x7f]), vec![0x7f]);
+		assert_eq!(encode_mpint(&[0x80, 1]), vec![0, 0x80, 1]);
+	}
+
+	#[test]
+	fn decodes_mpints() {
+		assert_eq!(decode_mpint(&[]), Ok(&[][..]));
+		assert_eq!(decode_mpint(&[0x7f]), Ok(&[0x7f][..]));
+		assert_eq!(decode_mpint(&[0, 0x80, 1]), Ok(&[0x80, 1][..]));
+	}
+
+	#[test]
+	fn rejects_negative_mpints() {
+		assert_eq!(decode_mpint(&[0x80]), Err("Negative SSH mpint"));
+		assert_eq!(decode_mpint(&[0xff, 0]), Err("Negative SSH mpint"));
+	}
+
+	#[test]
+	fn rejects_needless_leading_zeros() {
+		assert_eq!(decode_mpint(&[0]), Err("SSH mpint with a needless leading zero"));
+		assert_eq!(decode_mpint(&[0, 0x7f]), Err("SSH mpint with a needless leading zero"));
+		assert_eq!(decode_mpint(&[0, 0, 0x80]), Err("SSH mpint with a needless leading zero"));
+	}
+
+	#[test]
+	fn reads_mpints_through_the_reader() {
+		let mut reader = Reader::new(&[0, 0, 0, 2, 0, 0x80, 0, 0, 0, 1, 0]);
+		assert_eq!(reader.read_mpint(), Ok(&[0x80][..]));
+		assert!(reader.read_mpint().is_err());
+	}
+}